@@ -0,0 +1,13 @@
+-- Append-only record of every change made to the mesh, see `audit.rs`.
+create table if not exists AuditLog (
+    id bigint not null auto_increment,
+    at bigint not null,
+    actor varchar(191) not null,
+    action varchar(64) not null,
+    node_id varchar(191) null,
+    ip varchar(45) null,
+    detail text not null,
+    primary key (id),
+    index AuditLog_at (at),
+    index AuditLog_node_id_at (node_id, at)
+);
@@ -0,0 +1,26 @@
+-- State transitions and health check rollups the uptime report is computed from, see `history.rs`.
+create table if not exists NodeStateHistory (
+    id bigint not null auto_increment,
+    node_id varchar(191) not null,
+    ip varchar(45) not null,
+    country varchar(191) not null,
+    state varchar(32) not null,
+    cause varchar(255) not null,
+    at bigint not null,
+    primary key (id),
+    index NodeStateHistory_node_id_at (node_id, at),
+    index NodeStateHistory_country (country)
+);
+
+create table if not exists NodeHealthRollup (
+    id bigint not null auto_increment,
+    node_id varchar(191) not null,
+    country varchar(191) not null,
+    window_start bigint not null,
+    window_end bigint not null,
+    successes bigint not null,
+    failures bigint not null,
+    primary key (id),
+    index NodeHealthRollup_node_id_window (node_id, window_end),
+    index NodeHealthRollup_country (country)
+);
@@ -0,0 +1,9 @@
+-- Location, capacity and software details published so clients can make a better choice of server.
+alter table Server
+    add column region varchar(191) not null default '',
+    add column city varchar(191) not null default '',
+    add column latitude double not null default 0,
+    add column longitude double not null default 0,
+    add column capacity bigint null,
+    add column protocols varchar(255) null,
+    add column version varchar(64) null;
@@ -0,0 +1,4 @@
+-- Load and last seen time of each server, synced from the health checks every `DIRECTORY_SYNC_INTERVAL`.
+alter table Server
+    add column current_load double null,
+    add column last_seen bigint null;
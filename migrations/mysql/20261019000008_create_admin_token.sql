@@ -0,0 +1,11 @@
+-- Scoped tokens for the admin API, see `admin.rs`. Only the SHA-256 hash of each token is stored.
+create table if not exists AdminToken (
+    id varchar(191) not null,
+    name varchar(191) not null,
+    scope varchar(32) not null,
+    token_hash char(64) not null,
+    created_at bigint not null,
+    revoked_at bigint null,
+    primary key (id),
+    unique index AdminToken_token_hash (token_hash)
+);
@@ -0,0 +1,10 @@
+-- The public server directory read by the reseda clients, one row per published node.
+create table if not exists Server (
+    id varchar(191) not null,
+    ip varchar(45) not null,
+    location varchar(191) not null,
+    country varchar(191) not null,
+    hostname varchar(191) not null,
+    flag varchar(191) not null,
+    primary key (id)
+);
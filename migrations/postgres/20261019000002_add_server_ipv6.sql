@@ -0,0 +1,2 @@
+-- Dual-stack nodes publish their IPv6 address alongside the IPv4 one.
+alter table Server add column if not exists ipv6 varchar(45) null;
@@ -0,0 +1,13 @@
+-- Append-only record of every change made to the mesh, see `audit.rs`.
+create table if not exists AuditLog (
+    id bigserial primary key,
+    at bigint not null,
+    actor varchar(191) not null,
+    action varchar(64) not null,
+    node_id varchar(191) null,
+    ip varchar(45) null,
+    detail text not null
+);
+
+create index if not exists AuditLog_at on AuditLog (at);
+create index if not exists AuditLog_node_id_at on AuditLog (node_id, at);
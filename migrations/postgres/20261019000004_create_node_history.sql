@@ -0,0 +1,26 @@
+-- State transitions and health check rollups the uptime report is computed from, see `history.rs`.
+create table if not exists NodeStateHistory (
+    id bigserial primary key,
+    node_id varchar(191) not null,
+    ip varchar(45) not null,
+    country varchar(191) not null,
+    state varchar(32) not null,
+    cause varchar(255) not null,
+    at bigint not null
+);
+
+create index if not exists NodeStateHistory_node_id_at on NodeStateHistory (node_id, at);
+create index if not exists NodeStateHistory_country on NodeStateHistory (country);
+
+create table if not exists NodeHealthRollup (
+    id bigserial primary key,
+    node_id varchar(191) not null,
+    country varchar(191) not null,
+    window_start bigint not null,
+    window_end bigint not null,
+    successes bigint not null,
+    failures bigint not null
+);
+
+create index if not exists NodeHealthRollup_node_id_window on NodeHealthRollup (node_id, window_end);
+create index if not exists NodeHealthRollup_country on NodeHealthRollup (country);
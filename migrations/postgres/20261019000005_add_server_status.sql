@@ -0,0 +1,2 @@
+-- Degraded nodes stay in the directory but are no longer recommended to clients.
+alter table Server add column if not exists status varchar(32) not null default 'online';
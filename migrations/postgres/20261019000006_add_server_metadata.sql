@@ -0,0 +1,9 @@
+-- Location, capacity and software details published so clients can make a better choice of server.
+alter table Server
+    add column if not exists region varchar(191) not null default '',
+    add column if not exists city varchar(191) not null default '',
+    add column if not exists latitude double precision not null default 0,
+    add column if not exists longitude double precision not null default 0,
+    add column if not exists capacity bigint null,
+    add column if not exists protocols varchar(255) null,
+    add column if not exists version varchar(64) null;
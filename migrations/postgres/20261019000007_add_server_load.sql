@@ -0,0 +1,4 @@
+-- Load and last seen time of each server, synced from the health checks every `DIRECTORY_SYNC_INTERVAL`.
+alter table Server
+    add column if not exists current_load double precision null,
+    add column if not exists last_seen bigint null;
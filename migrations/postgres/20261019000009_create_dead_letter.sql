@@ -0,0 +1,14 @@
+-- Tasks which exhausted their retries, kept for an admin to inspect and re-drive, see `dead_letter.rs`.
+create table if not exists DeadLetter (
+    id varchar(36) not null,
+    task varchar(32) not null,
+    ip varchar(45) not null,
+    node_id varchar(191) null,
+    generation bigint not null,
+    tries integer not null,
+    last_error text null,
+    dead_at bigint not null,
+    primary key (id)
+);
+
+create index if not exists DeadLetter_dead_at on DeadLetter (dead_at);
@@ -0,0 +1,9 @@
+-- The public server directory read by the reseda clients, one row per published node.
+create table if not exists Server (
+    id text not null primary key,
+    ip text not null,
+    location text not null,
+    country text not null,
+    hostname text not null,
+    flag text not null
+);
@@ -0,0 +1,13 @@
+-- Append-only record of every change made to the mesh, see `audit.rs`.
+create table if not exists AuditLog (
+    id integer not null primary key autoincrement,
+    at bigint not null,
+    actor text not null,
+    action text not null,
+    node_id text null,
+    ip text null,
+    detail text not null
+);
+
+create index if not exists AuditLog_at on AuditLog (at);
+create index if not exists AuditLog_node_id_at on AuditLog (node_id, at);
@@ -0,0 +1,2 @@
+-- Degraded nodes stay in the directory but are no longer recommended to clients.
+alter table Server add column status text not null default 'online';
@@ -0,0 +1,8 @@
+-- Location, capacity and software details published so clients can make a better choice of server.
+alter table Server add column region text not null default '';
+alter table Server add column city text not null default '';
+alter table Server add column latitude real not null default 0;
+alter table Server add column longitude real not null default 0;
+alter table Server add column capacity bigint null;
+alter table Server add column protocols text null;
+alter table Server add column version text null;
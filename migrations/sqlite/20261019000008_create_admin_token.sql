@@ -0,0 +1,11 @@
+-- Scoped tokens for the admin API, see `admin.rs`. Only the SHA-256 hash of each token is stored.
+create table if not exists AdminToken (
+    id text not null primary key,
+    name text not null,
+    scope text not null,
+    token_hash text not null,
+    created_at bigint not null,
+    revoked_at bigint null
+);
+
+create unique index if not exists AdminToken_token_hash on AdminToken (token_hash);
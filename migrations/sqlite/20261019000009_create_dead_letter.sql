@@ -0,0 +1,13 @@
+-- Tasks which exhausted their retries, kept for an admin to inspect and re-drive, see `dead_letter.rs`.
+create table if not exists DeadLetter (
+    id text not null primary key,
+    task text not null,
+    ip text not null,
+    node_id text null,
+    generation bigint not null,
+    tries integer not null,
+    last_error text null,
+    dead_at bigint not null
+);
+
+create index if not exists DeadLetter_dead_at on DeadLetter (dead_at);
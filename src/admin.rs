@@ -0,0 +1,316 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+use chrono::Utc;
+use rand::RngCore;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{Any, Pool};
+use tracing::{error, warn};
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::reply::{json as json_reply, with_status};
+use warp::{Filter, Rejection, Reply};
+
+use crate::dialect::placeholders;
+use crate::errors::ErrorReply;
+use crate::routes::remote_address;
+
+/// Prefix of every admin token, so a leaked token is recognisable for what it is.
+const TOKEN_PREFIX: &str = "rsm_";
+
+/// What an admin token may do. Each scope includes everything the scopes before it may do.
+/// Tokens themselves are only managed from the command line, see `command`.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Reading the audit log, uptime report, webhook deliveries and dead letters.
+    ReadOnly,
+    /// Acting on nodes and their tasks, e.g. re-driving dead letters.
+    NodeOperator
+}
+
+impl Scope {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scope::ReadOnly => "read_only",
+            Scope::NodeOperator => "node_operator"
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Scope> {
+        match name {
+            "read_only" => Some(Scope::ReadOnly),
+            "node_operator" => Some(Scope::NodeOperator),
+            _ => None
+        }
+    }
+}
+
+/// An admin token as stored, only the SHA-256 hash of the secret is kept.
+#[derive(sqlx::FromRow, Debug)]
+struct TokenRecord {
+    id: String,
+    name: String,
+    scope: String
+}
+
+/// The caller of an admin route, authenticated by `require`.
+#[derive(Clone, Debug)]
+pub struct Admin {
+    pub token_id: String,
+    pub name: String,
+    pub remote: Option<SocketAddr>
+}
+
+impl Admin {
+    /// Identity recorded in the audit log for actions performed through the admin API, naming the token used.
+    pub fn actor(&self) -> String {
+        match self.remote {
+            Some(addr) => format!("admin:{}({})@{}", self.name, self.token_id, addr.ip()),
+            None => format!("admin:{}({})", self.name, self.token_id)
+        }
+    }
+}
+
+/// An admin request turned away by `require`, answered by `recover`.
+#[derive(Debug)]
+pub enum AdminRejected {
+    /// No token, or one which is unknown or revoked.
+    Unauthenticated,
+    /// A valid token without the scope the route requires.
+    Forbidden { scope: Scope, required: Scope },
+    /// The tokens could not be read from the database.
+    Unavailable
+}
+
+impl warp::reject::Reject for AdminRejected {}
+
+impl fmt::Display for AdminRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminRejected::Unauthenticated => write!(f, "a valid admin token is required, sent as Authorization: Bearer <token>"),
+            AdminRejected::Forbidden { scope, required } => write!(f, "token has the {} scope, {} is required", scope.name(), required.name()),
+            AdminRejected::Unavailable => write!(f, "unable to verify the admin token")
+        }
+    }
+}
+
+fn hash(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// Creates a token named `name` with `scope`, returning its id and the secret. The secret is not stored and cannot be shown again.
+pub async fn create_token(pool: &Pool<Any>, name: &str, scope: Scope) -> Result<(String, String), sqlx::Error> {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+
+    let id = Uuid::new_v4().to_string();
+    let secret = format!("{}{}", TOKEN_PREFIX, hex::encode(secret));
+
+    sqlx::query(&placeholders(pool, "insert into AdminToken (id, name, scope, token_hash, created_at) values (?, ?, ?, ?, ?)"))
+        .bind(&id)
+        .bind(name)
+        .bind(scope.name())
+        .bind(hash(&secret))
+        .bind(Utc::now().timestamp_millis())
+        .execute(pool)
+        .await?;
+
+    Ok((id, secret))
+}
+
+/// Revokes the token `id`, returning whether a token which was not yet revoked was found.
+pub async fn revoke_token(pool: &Pool<Any>, id: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(&placeholders(pool, "update AdminToken set revoked_at = ? where id = ? and revoked_at is null"))
+        .bind(Utc::now().timestamp_millis())
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+async fn find_token(pool: &Pool<Any>, secret: &str) -> Result<Option<TokenRecord>, sqlx::Error> {
+    sqlx::query_as::<_, TokenRecord>(&placeholders(pool, "select id, name, scope from AdminToken where token_hash = ? and revoked_at is null"))
+        .bind(hash(secret))
+        .fetch_optional(pool)
+        .await
+}
+
+/// Authenticates an admin route by the `Authorization: Bearer <token>` header, requiring a token with at least `required`.
+pub fn require(
+    required: Scope,
+    pool: Pool<Any>
+) -> impl Filter<Extract = (Admin,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(remote_address())
+        .and(warp::any().map(move || pool.clone()))
+        .and_then(move |authorization: Option<String>, remote: Option<SocketAddr>, pool: Pool<Any>| async move {
+            let secret = match authorization.as_deref().and_then(|header| header.strip_prefix("Bearer ")) {
+                Some(secret) if secret.starts_with(TOKEN_PREFIX) => secret.to_string(),
+                _ => return Err(warp::reject::custom(AdminRejected::Unauthenticated))
+            };
+
+            let token = match find_token(&pool, &secret).await {
+                Ok(Some(token)) => token,
+                Ok(None) => {
+                    warn!(remote = ?remote, "Rejected unknown or revoked admin token");
+                    return Err(warp::reject::custom(AdminRejected::Unauthenticated))
+                },
+                Err(err) => {
+                    error!(error = %err, "Unable to look up admin token");
+                    return Err(warp::reject::custom(AdminRejected::Unavailable))
+                }
+            };
+
+            let scope = match Scope::parse(&token.scope) {
+                Some(scope) => scope,
+                None => {
+                    error!(token = %token.id, scope = %token.scope, "Admin token has an unknown scope");
+                    return Err(warp::reject::custom(AdminRejected::Unauthenticated))
+                }
+            };
+
+            if scope < required {
+                warn!(token = %token.id, scope = scope.name(), required = required.name(), "Admin token lacks the required scope");
+                return Err(warp::reject::custom(AdminRejected::Forbidden { scope, required }))
+            }
+
+            Ok(Admin { token_id: token.id, name: token.name, remote })
+        })
+}
+
+/// Answers an `AdminRejected` rejection with `401`, `403` or `503` and a JSON error, any other rejection is passed on untouched.
+pub async fn recover(rejection: Rejection) -> Result<Box<dyn Reply>, Rejection> {
+    let rejected = match rejection.find::<AdminRejected>() {
+        Some(rejected) => rejected,
+        None => return Err(rejection)
+    };
+
+    let (code, status) = match rejected {
+        AdminRejected::Unauthenticated => ("admin_token_invalid", StatusCode::UNAUTHORIZED),
+        AdminRejected::Forbidden { .. } => ("admin_scope_insufficient", StatusCode::FORBIDDEN),
+        AdminRejected::Unavailable => ("admin_token_unavailable", StatusCode::SERVICE_UNAVAILABLE)
+    };
+
+    let body = ErrorReply::new(code, rejected.to_string());
+
+    Ok(Box::new(with_status(json_reply(&body), status)))
+}
+
+/// Runs `reseda-mesh token <command>`:
+///
+/// - `token create <name> <read_only|node_operator>` prints the new token's id and secret.
+/// - `token revoke <id>` revokes a token.
+///
+/// Returns whether the command succeeded.
+pub async fn command(pool: &Pool<Any>, arguments: &[String]) -> bool {
+    match arguments.iter().map(String::as_str).collect::<Vec<&str>>().as_slice() {
+        ["create", name, scope] => {
+            let scope = match Scope::parse(scope) {
+                Some(scope) => scope,
+                None => {
+                    eprintln!("unknown scope {}, expected read_only or node_operator", scope);
+                    return false
+                }
+            };
+
+            match create_token(pool, name, scope).await {
+                Ok((id, secret)) => {
+                    println!("id:     {}\nscope:  {}\ntoken:  {}\n\nThe token is not stored and cannot be shown again.", id, scope.name(), secret);
+                    true
+                },
+                Err(err) => {
+                    error!(error = %err, "Unable to create admin token");
+                    false
+                }
+            }
+        },
+        ["revoke", id] => match revoke_token(pool, id).await {
+            Ok(true) => {
+                println!("revoked {}", id);
+                true
+            },
+            Ok(false) => {
+                eprintln!("no active token with id {}", id);
+                false
+            },
+            Err(err) => {
+                error!(error = %err, "Unable to revoke admin token");
+                false
+            }
+        },
+        _ => {
+            eprintln!("usage: reseda-mesh token create <name> <read_only|node_operator>\n       reseda-mesh token revoke <id>");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::any::AnyPoolOptions;
+
+    use super::*;
+    use crate::migrate;
+
+    async fn pool() -> Pool<Any> {
+        let pool = AnyPoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+
+        migrate::run(&pool).await.unwrap();
+        pool
+    }
+
+    /// The status an operator route answers a request bearing `authorization` with.
+    async fn operator_route(pool: &Pool<Any>, authorization: Option<String>) -> StatusCode {
+        let route = require(Scope::NodeOperator, pool.clone())
+            .map(|_admin: Admin| StatusCode::OK)
+            .recover(recover);
+
+        let request = match authorization {
+            Some(authorization) => warp::test::request().header("authorization", authorization),
+            None => warp::test::request()
+        };
+
+        request.reply(&route).await.status()
+    }
+
+    #[tokio::test]
+    async fn operator_routes_require_the_operator_scope() {
+        let pool = pool().await;
+
+        let (_, operator) = create_token(&pool, "operator", Scope::NodeOperator).await.unwrap();
+        let (_, reader) = create_token(&pool, "dashboard", Scope::ReadOnly).await.unwrap();
+
+        assert_eq!(operator_route(&pool, Some(format!("Bearer {}", operator))).await, StatusCode::OK);
+        assert_eq!(operator_route(&pool, Some(format!("Bearer {}", reader))).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn revoked_tokens_are_rejected() {
+        let pool = pool().await;
+        let (id, secret) = create_token(&pool, "operator", Scope::NodeOperator).await.unwrap();
+
+        assert!(revoke_token(&pool, &id).await.unwrap());
+        assert_eq!(operator_route(&pool, Some(format!("Bearer {}", secret))).await, StatusCode::UNAUTHORIZED);
+
+        // Already revoked.
+        assert!(!revoke_token(&pool, &id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_known_token_are_rejected() {
+        let pool = pool().await;
+
+        assert_eq!(operator_route(&pool, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(operator_route(&pool, Some("Bearer rsm_unknown".to_string())).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(operator_route(&pool, Some("Basic cnNtXw==".to_string())).await, StatusCode::UNAUTHORIZED);
+    }
+}
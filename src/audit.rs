@@ -0,0 +1,153 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{Any, Pool};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tracing::error;
+
+use crate::dialect::placeholders;
+use crate::events::{EventBus, NodeEvent};
+use crate::models::RegistryReturn;
+
+/// Actor recorded for changes the mesh makes on its own accord (task runner, rollbacks).
+pub const MESH_ACTOR: &str = "mesh";
+
+/// Queues entries for the audit writer. Sending never blocks the caller, the database write happens in the background.
+pub type AuditLog = mpsc::UnboundedSender<AuditEntry>;
+
+/// An entry to be appended to the `AuditLog` table.
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub actor: String,
+    pub action: String,
+    pub node_id: Option<String>,
+    pub ip: Option<String>,
+    pub detail: String,
+    pub at: i64
+}
+
+impl AuditEntry {
+    pub fn new(actor: &str, action: &str, detail: String) -> Self {
+        AuditEntry {
+            actor: actor.to_string(),
+            action: action.to_string(),
+            node_id: None,
+            ip: None,
+            detail,
+            at: Utc::now().timestamp_millis()
+        }
+    }
+
+    /// An entry concerning the given node.
+    pub fn for_node(actor: &str, action: &str, node: &RegistryReturn, detail: String) -> Self {
+        AuditEntry {
+            node_id: Some(node.id.clone()),
+            ip: Some(node.ip.clone()),
+            ..AuditEntry::new(actor, action, detail)
+        }
+    }
+}
+
+/// A row of the `AuditLog` table, as returned by the admin API.
+#[derive(sqlx::FromRow, Serialize, Debug)]
+pub struct AuditRecord {
+    pub id: i64,
+    pub at: i64,
+    pub actor: String,
+    pub action: String,
+    pub node_id: Option<String>,
+    pub ip: Option<String>,
+    pub detail: String
+}
+
+/// Filters accepted by `GET /admin/audit`. Times are unix milliseconds and inclusive.
+#[derive(Deserialize, Debug, Default)]
+pub struct AuditQuery {
+    pub node: Option<String>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub limit: Option<u32>
+}
+
+/// Appends an entry to the audit log.
+pub fn record(log: &AuditLog, entry: AuditEntry) {
+    if let Err(err) = log.send(entry) {
+        error!(entry = ?err.0, "Audit writer has stopped, entry lost");
+    }
+}
+
+/// Starts the background writer which appends entries to the `AuditLog` table, and records
+/// every lifecycle transition published on the event bus.
+///
+/// The table is append-only; the mesh never updates or deletes rows from it.
+pub fn spawn_writer(pool: Pool<Any>, bus: &EventBus) -> AuditLog {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<AuditEntry>();
+
+    tokio::spawn(async move {
+        while let Some(entry) = receiver.recv().await {
+            let result = sqlx::query(&placeholders(&pool, "insert into AuditLog (at, actor, action, node_id, ip, detail) values (?, ?, ?, ?, ?, ?)"))
+                .bind(entry.at)
+                .bind(&entry.actor)
+                .bind(&entry.action)
+                .bind(&entry.node_id)
+                .bind(&entry.ip)
+                .bind(&entry.detail)
+                .execute(&pool)
+                .await;
+
+            if let Err(err) = result {
+                error!(entry = ?entry, error = %err, "Unable to write audit entry");
+            }
+        }
+    });
+
+    let mut events = bus.subscribe();
+    let log = sender.clone();
+
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    error!(skipped, "Audit writer lagged behind, lifecycle events were not recorded");
+                    continue;
+                },
+                Err(RecvError::Closed) => return
+            };
+
+            let (action, node, ip, detail) = match event {
+                NodeEvent::StateChanged { node, ip, from, to, cause, .. } => {
+                    ("state_changed", node, ip, format!("{} -> {}: {}", from.unwrap_or("none".to_string()), to, cause))
+                },
+                NodeEvent::InstantiationFailed { node, ip, .. } => {
+                    ("instantiation_failed", node, ip, "node never became healthy, instantiation dead-lettered".to_string())
+                },
+                NodeEvent::Purged { node, ip, .. } => {
+                    ("purged", node, ip, "node removed from the mesh".to_string())
+                },
+                // Individual failed checks are visible in the metrics, only the resulting transitions are audited.
+                NodeEvent::HealthCheckFailed { .. } => continue
+            };
+
+            record(&log, AuditEntry {
+                node_id: Some(node),
+                ip: Some(ip),
+                ..AuditEntry::new(MESH_ACTOR, action, detail)
+            });
+        }
+    });
+
+    sender
+}
+
+/// Reads entries matching `query`, newest first.
+pub async fn query(pool: &Pool<Any>, query: &AuditQuery) -> Result<Vec<AuditRecord>, sqlx::Error> {
+    sqlx::query_as::<_, AuditRecord>(&placeholders(pool, "select id, at, actor, action, node_id, ip, detail from AuditLog where (? is null or node_id = ?) and at >= ? and at <= ? order by at desc, id desc limit ?"))
+        .bind(&query.node)
+        .bind(&query.node)
+        .bind(query.from.unwrap_or(0))
+        .bind(query.to.unwrap_or(i64::MAX))
+        .bind(query.limit.unwrap_or(100).min(1000) as i64)
+        .fetch_all(pool)
+        .await
+}
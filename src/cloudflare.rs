@@ -0,0 +1,154 @@
+use std::net::IpAddr;
+
+use reqwest::Client;
+use rcgen::generate_simple_self_signed;
+
+use crate::errors::{RegistrationError, ProviderError, ProviderStep};
+use crate::metrics::{observe_provider, PROVIDER_DURATION};
+use crate::models::{CloudflareDNSRecordCreate, CloudflareDNSRecordCreateResult, CloudflareReturn, CloudflareDelete};
+
+/// Points `identifier` at the node, using an `A` record for IPv4 addresses and an `AAAA` record for IPv6 addresses.
+pub async fn create_dns_records(
+    cloudflare_key: &String,
+    client: &Client,
+    identifier: &String,
+    ip: &IpAddr,
+    proxied: bool
+) -> Result<CloudflareDNSRecordCreateResult, RegistrationError> {
+    let record_type = match ip {
+        IpAddr::V4(_) => "A",
+        IpAddr::V6(_) => "AAAA"
+    };
+
+    let timer = PROVIDER_DURATION.with_label_values(&["create_dns_record"]).start_timer();
+    let response = match client.post("https://api.cloudflare.com/client/v4/zones/ebb52f1687a35641237774c39391ba2a/dns_records")
+        .body(format!("
+        {{
+            \"type\": \"{}\",
+            \"name\": \"{}\",
+            \"content\": \"{}\",
+            \"ttl\": 3600,
+            \"priority\": 10,
+            \"proxied\": {}
+        }}", record_type, identifier, ip, proxied))
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {}",  cloudflare_key))
+        .send().await {
+            Ok(response) => {
+                match response.json::<CloudflareDNSRecordCreate>().await {
+                    Ok(r) => r.into_result(),
+                    Err(err) => Err(ProviderError::Malformed(err.to_string())),
+                }
+            },
+            Err(err) => Err(ProviderError::Transport(err.to_string())),
+        };
+
+    timer.observe_duration();
+    observe_provider("create_dns_record", &response);
+
+    response.map_err(|err| RegistrationError::Provider(ProviderStep::DnsRecord, err))
+}
+
+pub async fn create_certificates(
+    cloudflare_key: &String,
+    client: &Client,
+    id: &String
+) -> Result<(String, String, String), RegistrationError> {
+    let cert = match generate_simple_self_signed(vec![format!("{}.reseda.app", id.to_string())]) {
+        Ok(r) => r,
+        Err(err) => {
+            return Err(RegistrationError::CertificateGeneration(err.to_string()))
+        },
+    };
+
+    let cert_public = match cert.serialize_request_pem() {
+        Ok(r) => r,
+        Err(err) => {
+            return Err(RegistrationError::CertificateGeneration(err.to_string()))
+        },
+    };
+
+    let cert_private = cert.serialize_private_key_pem();
+    let cert_string = cert_public.replace("\r", "").split("\n").collect::<Vec<&str>>().join("\\n");
+    
+    let timer = PROVIDER_DURATION.with_label_values(&["create_certificate"]).start_timer();
+    let response = match client.post("https://api.cloudflare.com/client/v4/certificates")
+        .body(format!("
+        {{
+            \"hostnames\": [
+                \"{}.reseda.app\"
+            ],
+            \"requested_validity\": 5475,
+            \"request_type\": \"origin-rsa\",
+            \"csr\": \"{}\"
+        }}", id, cert_string))
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {}", cloudflare_key))
+        .send().await {
+            Ok(response) => {
+                match response.json::<CloudflareReturn>().await {
+                    Ok(r) => r.into_result(),
+                    Err(err) => Err(ProviderError::Malformed(err.to_string()))
+                }
+            },
+            Err(err) => Err(ProviderError::Transport(err.to_string())),
+        };
+
+    timer.observe_duration();
+    observe_provider("create_certificate", &response);
+
+    match response {
+        Ok(result) => Ok((result.certificate, cert_private, result.id)),
+        Err(err) => Err(RegistrationError::Provider(ProviderStep::Certificate, err))
+    }
+}
+
+/// Removes a DNS record from the reseda.app zone.
+pub async fn delete_dns_record(
+    cloudflare_key: &String,
+    client: &Client,
+    record_id: &String
+) -> Result<(), ProviderError> {
+    let timer = PROVIDER_DURATION.with_label_values(&["delete_dns_record"]).start_timer();
+    let response = match client.delete(format!("https://api.cloudflare.com/client/v4/zones/ebb52f1687a35641237774c39391ba2a/dns_records/{}", record_id))
+        .header("Authorization", format!("Bearer {}", cloudflare_key))
+        .send().await {
+            Ok(response) => {
+                match response.json::<CloudflareDelete>().await {
+                    Ok(r) => r.into_result().map(|_| ()),
+                    Err(err) => Err(ProviderError::Malformed(err.to_string())),
+                }
+            },
+            Err(err) => Err(ProviderError::Transport(err.to_string())),
+        };
+
+    timer.observe_duration();
+    observe_provider("delete_dns_record", &response);
+
+    response
+}
+
+/// Revokes an origin certificate previously issued by `create_certificates`.
+pub async fn revoke_certificate(
+    cloudflare_key: &String,
+    client: &Client,
+    cert_id: &String
+) -> Result<(), ProviderError> {
+    let timer = PROVIDER_DURATION.with_label_values(&["revoke_certificate"]).start_timer();
+    let response = match client.delete(format!("https://api.cloudflare.com/client/v4/certificates/{}", cert_id))
+        .header("Authorization", format!("Bearer {}", cloudflare_key))
+        .send().await {
+            Ok(response) => {
+                match response.json::<CloudflareDelete>().await {
+                    Ok(r) => r.into_result().map(|_| ()),
+                    Err(err) => Err(ProviderError::Malformed(err.to_string())),
+                }
+            },
+            Err(err) => Err(ProviderError::Transport(err.to_string())),
+        };
+
+    timer.observe_duration();
+    observe_provider("revoke_certificate", &response);
+
+    response
+}
@@ -0,0 +1,167 @@
+use chrono::Utc;
+use serde::Serialize;
+use sqlx::{Any, Pool};
+use tracing::error;
+use uuid::Uuid;
+
+use crate::audit::{record, AuditEntry, AuditLog, MESH_ACTOR};
+use crate::dialect::placeholders;
+use crate::models::{Task, TaskType};
+
+/// Number of dead letters listed, the most recent first.
+const DEAD_LETTER_LIMIT: i64 = 500;
+
+/// A task which exhausted its retry policy, kept in the `DeadLetter` table for an admin to inspect and re-drive.
+///
+/// Stored rather than held in memory so that none are lost when the mesh restarts. The nodes they refer to are not
+/// stored, so a letter left from before a restart can be inspected but not re-driven, see `handlers::redrive_dead_letter`.
+#[derive(sqlx::FromRow, Serialize, Clone, Debug)]
+pub struct DeadLetter {
+    pub id: String,
+    pub task: String,
+    pub ip: String,
+    #[sqlx(rename = "node_id")]
+    pub node: Option<String>,
+    pub generation: i64,
+    pub tries: i32,
+    pub last_error: Option<String>,
+    pub dead_at: i64
+}
+
+impl DeadLetter {
+    /// The task to queue when re-driving this letter, starting over with a fresh retry budget.
+    pub fn redrive(&self) -> Option<Task> {
+        Some(Task {
+            task_type: TaskType::parse(&self.task)?,
+            action_object: self.ip.clone(),
+            exec_at: Utc::now().timestamp_millis() as u128,
+            generation: self.generation as u64,
+            last_error: None
+        })
+    }
+}
+
+/// Moves an exhausted task into the dead letter store.
+pub async fn bury(
+    pool: &Pool<Any>,
+    audit: &AuditLog,
+    task: &Task,
+    node: Option<String>
+) {
+    let letter = DeadLetter {
+        id: Uuid::new_v4().to_string(),
+        task: task.task_type.name().to_string(),
+        ip: task.action_object.clone(),
+        node,
+        generation: task.generation as i64,
+        tries: task.task_type.tries() as i32,
+        last_error: task.last_error.clone(),
+        dead_at: Utc::now().timestamp_millis()
+    };
+
+    error!(letter = %letter.id, task = %letter.task, tries = letter.tries, last_error = ?letter.last_error, "Task exhausted its retries, dead-lettered");
+
+    record(audit, AuditEntry {
+        node_id: letter.node.clone(),
+        ip: Some(letter.ip.clone()),
+        ..AuditEntry::new(MESH_ACTOR, "task_dead_lettered", format!("{} {} after {} tries: {}", letter.task, letter.id, letter.tries, letter.last_error.clone().unwrap_or_default()))
+    });
+
+    let stored = sqlx::query(&placeholders(pool, "insert into DeadLetter (id, task, ip, node_id, generation, tries, last_error, dead_at) values (?, ?, ?, ?, ?, ?, ?, ?)"))
+        .bind(&letter.id)
+        .bind(&letter.task)
+        .bind(&letter.ip)
+        .bind(&letter.node)
+        .bind(letter.generation)
+        .bind(letter.tries)
+        .bind(&letter.last_error)
+        .bind(letter.dead_at)
+        .execute(pool)
+        .await;
+
+    // The audit log entry above still records the letter.
+    if let Err(err) = stored {
+        error!(letter = %letter.id, error = %err, "Unable to store dead letter");
+    }
+}
+
+/// The most recent dead letters, newest first.
+pub async fn list(pool: &Pool<Any>) -> Result<Vec<DeadLetter>, sqlx::Error> {
+    sqlx::query_as::<_, DeadLetter>(&placeholders(pool, "select id, task, ip, node_id, generation, tries, last_error, dead_at from DeadLetter order by dead_at desc limit ?"))
+        .bind(DEAD_LETTER_LIMIT)
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn find(pool: &Pool<Any>, id: &str) -> Result<Option<DeadLetter>, sqlx::Error> {
+    sqlx::query_as::<_, DeadLetter>(&placeholders(pool, "select id, task, ip, node_id, generation, tries, last_error, dead_at from DeadLetter where id = ?"))
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Removes a letter once it is re-driven. Returns whether it was still there, so a letter is only ever re-driven once.
+pub async fn remove(pool: &Pool<Any>, id: &str) -> Result<bool, sqlx::Error> {
+    let removed = sqlx::query(&placeholders(pool, "delete from DeadLetter where id = ?"))
+        .bind(id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+
+    Ok(removed > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::any::AnyPoolOptions;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::migrate;
+
+    async fn pool() -> Pool<Any> {
+        let pool = AnyPoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+
+        migrate::run(&pool).await.unwrap();
+        pool
+    }
+
+    #[tokio::test]
+    async fn buried_tasks_are_listed_and_redriven_once() {
+        let pool = pool().await;
+        let (audit, _entries) = mpsc::unbounded_channel();
+
+        let task = Task {
+            task_type: TaskType::Instantiate(6),
+            action_object: "10.0.0.1".to_string(),
+            exec_at: 0,
+            generation: 3,
+            last_error: Some("health check failed: timed out".to_string())
+        };
+
+        bury(&pool, &audit, &task, Some("node".to_string())).await;
+
+        let letters = list(&pool).await.unwrap();
+        assert_eq!(letters.len(), 1);
+
+        let letter = find(&pool, &letters[0].id).await.unwrap().unwrap();
+        assert_eq!(letter.task, "instantiate");
+        assert_eq!(letter.tries, 6);
+        assert_eq!(letter.last_error.as_deref(), Some("health check failed: timed out"));
+
+        let redriven = letter.redrive().unwrap();
+        assert!(matches!(redriven.task_type, TaskType::Instantiate(0)));
+        assert_eq!(redriven.generation, 3);
+        assert_eq!(redriven.action_object, "10.0.0.1");
+
+        assert!(remove(&pool, &letter.id).await.unwrap());
+        assert!(!remove(&pool, &letter.id).await.unwrap());
+        assert!(list(&pool).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,72 @@
+use std::borrow::Cow;
+
+use sqlx::{Any, Pool};
+use sqlx::any::AnyKind;
+
+/// Rewrites the `?` bind markers of `query` into the `$1, $2, ...` form Postgres expects, the `Any` driver
+/// passes queries through untouched. MySQL and SQLite queries are returned as they are.
+///
+/// Every query of the mesh is written with `?` and goes through here.
+pub fn placeholders<'q>(pool: &Pool<Any>, query: &'q str) -> Cow<'q, str> {
+    match pool.any_kind() {
+        AnyKind::Postgres => {
+            let mut rewritten = String::with_capacity(query.len() + 8);
+            let mut index = 0;
+            let mut quoted = false;
+
+            for c in query.chars() {
+                match c {
+                    '\'' => {
+                        quoted = !quoted;
+                        rewritten.push(c);
+                    },
+                    '?' if !quoted => {
+                        index += 1;
+                        rewritten.push_str(&format!("${}", index));
+                    },
+                    _ => rewritten.push(c)
+                }
+            }
+
+            Cow::Owned(rewritten)
+        },
+        AnyKind::MySql | AnyKind::Sqlite => Cow::Borrowed(query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::any::AnyPoolOptions;
+
+    use super::*;
+
+    /// A pool of the given kind, no connection is made until it is used.
+    fn pool(url: &str) -> Pool<Any> {
+        AnyPoolOptions::new().connect_lazy(url).unwrap()
+    }
+
+    #[tokio::test]
+    async fn numbers_postgres_placeholders() {
+        let pool = pool("postgres://localhost/mesh");
+
+        assert_eq!(placeholders(&pool, "select * from AuditLog where actor = ? and at > ? limit ?"), "select * from AuditLog where actor = $1 and at > $2 limit $3");
+        assert_eq!(placeholders(&pool, "select 1"), "select 1");
+    }
+
+    #[tokio::test]
+    async fn leaves_quoted_question_marks_alone() {
+        let pool = pool("postgres://localhost/mesh");
+
+        assert_eq!(placeholders(&pool, "select * from AuditLog where detail = '?' and actor = ?"), "select * from AuditLog where detail = '?' and actor = $1");
+        assert_eq!(placeholders(&pool, "select 'it''s?' where id = ?"), "select 'it''s?' where id = $1");
+    }
+
+    #[tokio::test]
+    async fn passes_other_databases_through() {
+        for url in ["mysql://localhost/mesh", "sqlite::memory:"] {
+            let query = "select * from AuditLog where actor = ?";
+
+            assert!(matches!(placeholders(&pool(url), query), Cow::Borrowed(q) if q == query));
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use futures_timer::Delay;
+use tokio::sync::Mutex;
+use tracing::{debug, error};
+
+use crate::directory::Directory;
+use crate::models::{Node, NodeStatusResponse};
+
+/// The most recent load reported by a node and when it was last seen healthy.
+#[derive(Debug, Clone)]
+pub struct LoadSample {
+    pub id: String,
+    /// The node's `usage` as reported by its health check, `None` when it is not a number,
+    /// in which case the published load is kept.
+    pub load: Option<f64>,
+    /// Unix milliseconds.
+    pub last_seen: i64
+}
+
+/// The latest sample per node id since the last sync, only the newest sample of a node is ever written.
+pub type LoadTally = Arc<Mutex<HashMap<String, LoadSample>>>;
+
+/// Records the load reported in a successful health check of `node`.
+pub async fn sample_load(tally: &LoadTally, node: &Node, status: &NodeStatusResponse) {
+    let sample = LoadSample {
+        id: node.information.id.clone(),
+        load: status.usage.trim().parse::<f64>().ok(),
+        last_seen: Utc::now().timestamp_millis()
+    };
+
+    tally.lock().await.insert(sample.id.clone(), sample);
+}
+
+/// Starts the background task which writes the sampled load of every node to the directory once per `interval`,
+/// so the public listing follows the nodes' load without a write for every health check.
+pub fn spawn_load_sync(directory: Directory, interval: Duration) -> LoadTally {
+    let tally: LoadTally = Arc::new(Mutex::new(HashMap::new()));
+    let sync_tally = tally.clone();
+
+    tokio::spawn(async move {
+        loop {
+            Delay::new(interval).await;
+
+            let samples = mem::take(&mut *sync_tally.lock().await)
+                .into_values()
+                .collect::<Vec<LoadSample>>();
+
+            if samples.is_empty() {
+                continue;
+            }
+
+            match directory.record_load(&samples).await {
+                Ok(updated) => debug!(samples = samples.len(), updated, "Synced node load to the server directory"),
+                // The next health check of each node samples it again, so nothing is requeued
+                Err(err) => error!(samples = samples.len(), error = %err, "Unable to sync node load to the server directory")
+            }
+        }
+    });
+
+    tally
+}
@@ -0,0 +1,100 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::Serialize;
+use sqlx::{Any, Pool};
+
+use crate::models::{Node, NodeState, NodeStatusResponse};
+
+mod load;
+mod sql;
+
+pub use self::load::{sample_load, spawn_load_sync, LoadSample, LoadTally};
+pub use self::sql::SqlDirectory;
+
+/// A node as published in the `Server` table, which the reseda clients choose their server from.
+#[derive(sqlx::FromRow, Serialize, Clone, Debug)]
+pub struct ServerRecord {
+    pub id: String,
+    pub ip: String,
+    pub ipv6: Option<String>,
+    pub location: String,
+    pub country: String,
+    pub hostname: String,
+    pub flag: String,
+    pub status: String,
+    pub region: String,
+    pub city: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub capacity: Option<i64>,
+    /// Comma separated, e.g. `wireguard,openvpn`.
+    pub protocols: Option<String>,
+    pub version: Option<String>,
+    /// Kept up to date by `spawn_load_sync` rather than on publish.
+    pub current_load: Option<f64>,
+    pub last_seen: Option<i64>
+}
+
+impl ServerRecord {
+    /// The row published for `node` once it is online, combining what ip-api resolved for the node at registration
+    /// with what the node reported about itself in its health check (`status`).
+    pub fn new(node: &Node, status: &NodeStatusResponse) -> Self {
+        let information = &node.information;
+        let timezone = &information.res.timezone;
+
+        ServerRecord {
+            id: information.id.clone(),
+            ip: information.ipv4().unwrap_or(information.ip.clone()),
+            ipv6: information.ipv6(),
+            location: timezone.clone(),
+            country: timezone.split("/").nth(1).unwrap_or(timezone).to_string(),
+            hostname: format!("{}.reseda.app", information.id),
+            flag: information.res.country.to_lowercase().replace(" ", "-"),
+            status: NodeState::Online.name().to_string(),
+            region: information.res.region.clone(),
+            city: information.res.city.clone(),
+            latitude: information.res.lat as f64,
+            longitude: information.res.lon as f64,
+            capacity: status.capacity,
+            protocols: match status.protocols.is_empty() {
+                true => None,
+                false => Some(status.protocols.join(","))
+            },
+            version: status.version.clone(),
+            current_load: None,
+            last_seen: None
+        }
+    }
+}
+
+/// Storage of the public server directory, the mesh itself only ever goes through this trait.
+#[async_trait]
+pub trait ServerDirectory: Send + Sync {
+    /// Publishes the server, updating the row already published under its id, if any.
+    ///
+    /// A row left behind under another id for the same address, e.g. by a node which re-registered while the
+    /// mesh was restarting, is removed in the same transaction. Returns the number of such stale rows replaced.
+    async fn publish(&self, server: &ServerRecord) -> Result<u64, sqlx::Error>;
+
+    /// Removes the server from the directory. Removing a server which is not published is not an error.
+    async fn unpublish(&self, id: &str) -> Result<(), sqlx::Error>;
+
+    /// Every published server.
+    async fn list(&self) -> Result<Vec<ServerRecord>, sqlx::Error>;
+
+    /// Sets the `status` of a published server, clients only recommend servers which are online.
+    async fn update_status(&self, id: &str, status: &NodeState) -> Result<(), sqlx::Error>;
+
+    /// Writes the load and last seen time of each sampled server in a single transaction.
+    /// Returns the number of published servers updated, samples of unpublished servers are ignored.
+    async fn record_load(&self, samples: &[LoadSample]) -> Result<u64, sqlx::Error>;
+}
+
+pub type Directory = Arc<dyn ServerDirectory>;
+
+/// The directory on the database `pool` is connected to, whichever the scheme of `$DATABASE_URL`
+/// (`mysql://...`, `postgres://...` or `sqlite:...`).
+pub fn for_pool(pool: &Pool<Any>) -> Directory {
+    Arc::new(SqlDirectory::new(pool.clone()))
+}
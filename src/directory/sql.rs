@@ -0,0 +1,242 @@
+use std::borrow::Cow;
+
+use async_trait::async_trait;
+use sqlx::{Any, Pool};
+use sqlx::any::AnyKind;
+
+use crate::dialect::placeholders;
+use crate::directory::{LoadSample, ServerDirectory, ServerRecord};
+use crate::models::NodeState;
+
+/// The server directory on whichever database `$DATABASE_URL` points at. MySQL is where the reseda clients
+/// read it from in production, Postgres is used by environments which standardise on it, and SQLite runs the
+/// mesh locally and in tests. Queries are written once with `?` bind markers, only the upsert differs.
+pub struct SqlDirectory {
+    pool: Pool<Any>
+}
+
+impl SqlDirectory {
+    pub fn new(pool: Pool<Any>) -> Self {
+        SqlDirectory { pool }
+    }
+
+    fn query<'q>(&self, query: &'q str) -> Cow<'q, str> {
+        placeholders(&self.pool, query)
+    }
+}
+
+/// The clause turning the insert of a server already published under its id into an update of its row.
+fn upsert(kind: AnyKind) -> &'static str {
+    match kind {
+        AnyKind::MySql => "on duplicate key update ip = values(ip), ipv6 = values(ipv6), location = values(location), country = values(country), hostname = values(hostname), flag = values(flag), status = values(status), region = values(region), city = values(city), latitude = values(latitude), longitude = values(longitude), capacity = values(capacity), protocols = values(protocols), version = values(version)",
+        AnyKind::Postgres | AnyKind::Sqlite => "on conflict (id) do update set ip = excluded.ip, ipv6 = excluded.ipv6, location = excluded.location, country = excluded.country, hostname = excluded.hostname, flag = excluded.flag, status = excluded.status, region = excluded.region, city = excluded.city, latitude = excluded.latitude, longitude = excluded.longitude, capacity = excluded.capacity, protocols = excluded.protocols, version = excluded.version"
+    }
+}
+
+#[async_trait]
+impl ServerDirectory for SqlDirectory {
+    async fn publish(&self, server: &ServerRecord) -> Result<u64, sqlx::Error> {
+        let mut transaction = self.pool.begin().await?;
+
+        let replaced = sqlx::query(&self.query("delete from Server where (ip = ? or ipv6 = ?) and id <> ?"))
+            .bind(&server.ip)
+            .bind(&server.ipv6)
+            .bind(&server.id)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected();
+
+        sqlx::query(&self.query(&format!("insert into Server (id, ip, ipv6, location, country, hostname, flag, status, region, city, latitude, longitude, capacity, protocols, version) values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) {}", upsert(self.pool.any_kind()))))
+            .bind(&server.id)
+            .bind(&server.ip)
+            .bind(&server.ipv6)
+            .bind(&server.location)
+            .bind(&server.country)
+            .bind(&server.hostname)
+            .bind(&server.flag)
+            .bind(&server.status)
+            .bind(&server.region)
+            .bind(&server.city)
+            .bind(server.latitude)
+            .bind(server.longitude)
+            .bind(server.capacity)
+            .bind(&server.protocols)
+            .bind(&server.version)
+            .execute(&mut transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok(replaced)
+    }
+
+    async fn unpublish(&self, id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(&self.query("delete from Server where id = ?"))
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<ServerRecord>, sqlx::Error> {
+        sqlx::query_as::<_, ServerRecord>("select id, ip, ipv6, location, country, hostname, flag, status, region, city, latitude, longitude, capacity, protocols, version, current_load, last_seen from Server order by id")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn update_status(&self, id: &str, status: &NodeState) -> Result<(), sqlx::Error> {
+        sqlx::query(&self.query("update Server set status = ? where id = ?"))
+            .bind(status.name())
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn record_load(&self, samples: &[LoadSample]) -> Result<u64, sqlx::Error> {
+        let mut transaction = self.pool.begin().await?;
+        let mut updated = 0;
+
+        for sample in samples {
+            updated += sqlx::query(&self.query("update Server set current_load = coalesce(?, current_load), last_seen = ? where id = ?"))
+                .bind(sample.load)
+                .bind(sample.last_seen)
+                .bind(&sample.id)
+                .execute(&mut transaction)
+                .await?
+                .rows_affected();
+        }
+
+        transaction.commit().await?;
+
+        Ok(updated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use sqlx::any::AnyPoolOptions;
+
+    use super::*;
+    use crate::directory::spawn_load_sync;
+    use crate::migrate;
+
+    /// A migrated in-memory database. Every connection to `sqlite::memory:` opens a database of its own,
+    /// so the pool is held to the one connection.
+    async fn directory() -> SqlDirectory {
+        let pool = AnyPoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+
+        migrate::run(&pool).await.unwrap();
+        SqlDirectory::new(pool)
+    }
+
+    fn server(id: &str, ip: &str) -> ServerRecord {
+        ServerRecord {
+            id: id.to_string(),
+            ip: ip.to_string(),
+            ipv6: None,
+            location: "Europe/Amsterdam".to_string(),
+            country: "Amsterdam".to_string(),
+            hostname: format!("{}.reseda.app", id),
+            flag: "netherlands".to_string(),
+            status: NodeState::Online.name().to_string(),
+            region: "NH".to_string(),
+            city: "Amsterdam".to_string(),
+            latitude: 52.37,
+            longitude: 4.89,
+            capacity: Some(100),
+            protocols: Some("wireguard".to_string()),
+            version: Some("1.0.0".to_string()),
+            current_load: None,
+            last_seen: None
+        }
+    }
+
+    #[test]
+    fn upserts_in_the_dialect_of_each_database() {
+        assert!(upsert(AnyKind::MySql).starts_with("on duplicate key update"));
+        assert!(upsert(AnyKind::Postgres).starts_with("on conflict (id) do update"));
+        assert_eq!(upsert(AnyKind::Postgres), upsert(AnyKind::Sqlite));
+    }
+
+    #[tokio::test]
+    async fn publish_upserts_by_id() {
+        let directory = directory().await;
+
+        assert_eq!(directory.publish(&server("a", "10.0.0.1")).await.unwrap(), 0);
+
+        let updated = ServerRecord { capacity: Some(250), version: Some("1.1.0".to_string()), ..server("a", "10.0.0.1") };
+        assert_eq!(directory.publish(&updated).await.unwrap(), 0);
+
+        let servers = directory.list().await.unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].capacity, Some(250));
+        assert_eq!(servers[0].version.as_deref(), Some("1.1.0"));
+    }
+
+    #[tokio::test]
+    async fn publish_replaces_stale_rows_for_the_same_address() {
+        let directory = directory().await;
+
+        directory.publish(&server("a", "10.0.0.1")).await.unwrap();
+        directory.publish(&server("b", "10.0.0.2")).await.unwrap();
+
+        assert_eq!(directory.publish(&server("c", "10.0.0.1")).await.unwrap(), 1);
+
+        let ids = directory.list().await.unwrap().into_iter().map(|s| s.id).collect::<Vec<String>>();
+        assert_eq!(ids, vec!["b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn unpublish_removes_the_server() {
+        let directory = directory().await;
+
+        directory.publish(&server("a", "10.0.0.1")).await.unwrap();
+        directory.update_status("a", &NodeState::Degraded).await.unwrap();
+        assert_eq!(directory.list().await.unwrap()[0].status, "degraded");
+
+        directory.unpublish("a").await.unwrap();
+        assert!(directory.list().await.unwrap().is_empty());
+
+        // Not published any more, which is not an error.
+        directory.unpublish("a").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn load_sync_updates_published_servers() {
+        let directory = Arc::new(directory().await);
+        directory.publish(&server("a", "10.0.0.1")).await.unwrap();
+
+        let tally = spawn_load_sync(directory.clone(), Duration::from_millis(10));
+
+        for (id, load) in [("a", Some(0.5)), ("unpublished", Some(0.9))] {
+            tally.lock().await.insert(id.to_string(), LoadSample { id: id.to_string(), load, last_seen: 1000 });
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let servers = directory.list().await.unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].current_load, Some(0.5));
+        assert_eq!(servers[0].last_seen, Some(1000));
+
+        // A sample without a load keeps the one published.
+        tally.lock().await.insert("a".to_string(), LoadSample { id: "a".to_string(), load: None, last_seen: 2000 });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let servers = directory.list().await.unwrap();
+        assert_eq!(servers[0].current_load, Some(0.5));
+        assert_eq!(servers[0].last_seen, Some(2000));
+    }
+}
@@ -0,0 +1,225 @@
+use std::fmt;
+
+use serde::Serialize;
+use warp::Reply;
+use warp::http::StatusCode;
+use warp::reply::{json as json_reply, with_status};
+
+use crate::models::{CloudflareMessage, NodeState};
+
+/// Failure modes of a request made to Cloudflare.
+#[derive(Debug, Clone)]
+pub enum ProviderError {
+    /// The request could not be sent, or the connection dropped before a reply arrived.
+    Transport(String),
+    /// A reply arrived but it did not match the expected response envelope.
+    Malformed(String),
+    /// Cloudflare answered the request with `success: false`.
+    Rejected {
+        errors: Vec<CloudflareMessage>,
+        messages: Vec<CloudflareMessage>
+    }
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProviderError::Transport(reason) => write!(f, "unable to reach provider: {}", reason),
+            ProviderError::Malformed(reason) => write!(f, "malformed provider response: {}", reason),
+            ProviderError::Rejected { errors, .. } => {
+                let reasons = errors.iter()
+                    .map(|e| format!("[{}] {}", e.code, e.message))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+
+                write!(f, "provider rejected request: {}", reasons)
+            }
+        }
+    }
+}
+
+/// A node was asked to move between two states the lifecycle does not connect.
+#[derive(Debug, Clone)]
+pub struct IllegalTransition {
+    pub node: String,
+    pub from: NodeState,
+    pub to: NodeState
+}
+
+impl fmt::Display for IllegalTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "node {} cannot move from {} to {}", self.node, self.from.name(), self.to.name())
+    }
+}
+
+/// The provisioning step a provider error occurred in.
+#[derive(Debug, Clone, Copy)]
+pub enum ProviderStep {
+    DnsRecord,
+    Certificate
+}
+
+/// Everything that can go wrong while registering a node, as reported back to the node.
+#[derive(Debug, Clone)]
+pub enum RegistrationError {
+    /// The address(es) supplied by the node are not valid, or not a usable dual-stack pair.
+    InvalidAddress(String),
+    /// ip-api was unable to resolve a location for the node.
+    Location(String),
+    /// The certificate signing request could not be generated locally.
+    CertificateGeneration(String),
+    /// Cloudflare failed or refused one of the provisioning requests.
+    Provider(ProviderStep, ProviderError),
+    /// The node is known to the mesh, but is being drained, has failed to instantiate or is quarantined and so may not register again.
+    NodeUnavailable(NodeState)
+}
+
+impl RegistrationError {
+    /// A stable, machine readable identifier for the error.
+    /// Nodes match on these, so existing values must never be changed.
+    pub fn code(&self) -> &'static str {
+        match self {
+            RegistrationError::InvalidAddress(_) => "invalid_address",
+            RegistrationError::Location(_) => "location_lookup_failed",
+            RegistrationError::CertificateGeneration(_) => "certificate_generation_failed",
+            RegistrationError::Provider(step, error) => match (step, error) {
+                (ProviderStep::DnsRecord, ProviderError::Transport(_)) => "dns_provider_unreachable",
+                (ProviderStep::DnsRecord, ProviderError::Malformed(_)) => "dns_provider_malformed_response",
+                (ProviderStep::DnsRecord, ProviderError::Rejected { .. }) => "dns_record_rejected",
+                (ProviderStep::Certificate, ProviderError::Transport(_)) => "certificate_provider_unreachable",
+                (ProviderStep::Certificate, ProviderError::Malformed(_)) => "certificate_provider_malformed_response",
+                (ProviderStep::Certificate, ProviderError::Rejected { .. }) => "certificate_rejected",
+            },
+            RegistrationError::NodeUnavailable(state) => match state {
+                NodeState::Draining => "node_draining",
+                NodeState::Failed => "node_failed",
+                NodeState::Quarantined => "node_quarantined",
+                _ => "node_unavailable"
+            }
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RegistrationError::InvalidAddress(_) => StatusCode::BAD_REQUEST,
+            RegistrationError::CertificateGeneration(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            RegistrationError::Location(_) | RegistrationError::Provider(_, _) => StatusCode::BAD_GATEWAY,
+            RegistrationError::NodeUnavailable(_) => StatusCode::CONFLICT
+        }
+    }
+
+    pub fn into_reply(self) -> Box<dyn Reply> {
+        let status = self.status();
+        let (provider_errors, provider_messages) = match &self {
+            RegistrationError::Provider(_, ProviderError::Rejected { errors, messages }) => (errors.clone(), messages.clone()),
+            _ => (vec![], vec![])
+        };
+
+        let body = ErrorReply {
+            error: ErrorDetail {
+                code: self.code(),
+                message: self.to_string(),
+                provider_errors,
+                provider_messages
+            }
+        };
+
+        Box::new(with_status(json_reply(&body), status))
+    }
+}
+
+impl fmt::Display for RegistrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistrationError::InvalidAddress(reason) => write!(f, "invalid address: {}", reason),
+            RegistrationError::Location(reason) => write!(f, "unable to determine node location: {}", reason),
+            RegistrationError::CertificateGeneration(reason) => write!(f, "unable to generate certificate request: {}", reason),
+            RegistrationError::Provider(ProviderStep::DnsRecord, error) => write!(f, "unable to create dns record, {}", error),
+            RegistrationError::Provider(ProviderStep::Certificate, error) => write!(f, "unable to create certificate, {}", error),
+            RegistrationError::NodeUnavailable(NodeState::Draining) => write!(f, "node is being dismissed, register again once it is offline"),
+            RegistrationError::NodeUnavailable(NodeState::Failed) => write!(f, "node never became healthy, it is purged unless its instantiation is re-driven"),
+            RegistrationError::NodeUnavailable(NodeState::Quarantined) => write!(f, "node is quarantined until it is purged"),
+            RegistrationError::NodeUnavailable(state) => write!(f, "node is {} and cannot register", state.name()),
+        }
+    }
+}
+
+/// JSON body returned to a node when a request fails.
+#[derive(Serialize, Debug)]
+pub struct ErrorReply {
+    pub error: ErrorDetail
+}
+
+impl ErrorReply {
+    /// A reply for an error which did not come from Cloudflare, so has no provider errors to pass on.
+    pub fn new(code: &'static str, message: String) -> Self {
+        ErrorReply {
+            error: ErrorDetail {
+                code,
+                message,
+                provider_errors: vec![],
+                provider_messages: vec![]
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ErrorDetail {
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub provider_errors: Vec<CloudflareMessage>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub provider_messages: Vec<CloudflareMessage>
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected() -> ProviderError {
+        ProviderError::Rejected {
+            errors: vec![CloudflareMessage { code: 1004, message: "DNS Validation Error".to_string() }],
+            messages: vec![]
+        }
+    }
+
+    #[test]
+    fn codes_are_stable() {
+        let cases = [
+            (RegistrationError::InvalidAddress("x".to_string()), "invalid_address"),
+            (RegistrationError::Location("x".to_string()), "location_lookup_failed"),
+            (RegistrationError::CertificateGeneration("x".to_string()), "certificate_generation_failed"),
+            (RegistrationError::Provider(ProviderStep::DnsRecord, ProviderError::Transport("x".to_string())), "dns_provider_unreachable"),
+            (RegistrationError::Provider(ProviderStep::DnsRecord, ProviderError::Malformed("x".to_string())), "dns_provider_malformed_response"),
+            (RegistrationError::Provider(ProviderStep::DnsRecord, rejected()), "dns_record_rejected"),
+            (RegistrationError::Provider(ProviderStep::Certificate, ProviderError::Transport("x".to_string())), "certificate_provider_unreachable"),
+            (RegistrationError::Provider(ProviderStep::Certificate, ProviderError::Malformed("x".to_string())), "certificate_provider_malformed_response"),
+            (RegistrationError::Provider(ProviderStep::Certificate, rejected()), "certificate_rejected"),
+            (RegistrationError::NodeUnavailable(NodeState::Draining), "node_draining"),
+            (RegistrationError::NodeUnavailable(NodeState::Failed), "node_failed"),
+            (RegistrationError::NodeUnavailable(NodeState::Quarantined), "node_quarantined")
+        ];
+
+        for (error, code) in cases {
+            assert_eq!(error.code(), code);
+        }
+    }
+
+    #[test]
+    fn statuses_follow_who_is_at_fault() {
+        assert_eq!(RegistrationError::InvalidAddress("x".to_string()).status(), StatusCode::BAD_REQUEST);
+        assert_eq!(RegistrationError::CertificateGeneration("x".to_string()).status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(RegistrationError::Location("x".to_string()).status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(RegistrationError::Provider(ProviderStep::Certificate, rejected()).status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(RegistrationError::NodeUnavailable(NodeState::Quarantined).status(), StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn rejections_list_the_provider_errors() {
+        let error = RegistrationError::Provider(ProviderStep::DnsRecord, rejected());
+
+        assert_eq!(error.to_string(), "unable to create dns record, provider rejected request: [1004] DNS Validation Error");
+    }
+}
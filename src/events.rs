@@ -0,0 +1,112 @@
+use chrono::Utc;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::models::{Node, NodeState};
+
+/// Number of events buffered per subscriber before a slow subscriber starts missing events.
+const EVENT_BUFFER: usize = 256;
+
+/// Publishes node lifecycle events to every subscriber (i.e. `/events` streams).
+pub type EventBus = broadcast::Sender<NodeEvent>;
+
+pub fn event_bus() -> EventBus {
+    let (sender, _) = broadcast::channel(EVENT_BUFFER);
+    sender
+}
+
+/// Something that happened to a node, serialized as `{ "type": "...", ... }`.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NodeEvent {
+    /// The node moved between lifecycle states. `from` is empty when the node was first registered.
+    StateChanged {
+        node: String,
+        ip: String,
+        country: String,
+        from: Option<String>,
+        to: String,
+        cause: String,
+        at: i64
+    },
+    /// A health check of the node went unanswered or returned an invalid response.
+    HealthCheckFailed {
+        node: String,
+        ip: String,
+        country: String,
+        tries: i16,
+        at: i64
+    },
+    /// The node never came up after registering, it is purged unless its instantiation is re-driven.
+    InstantiationFailed {
+        node: String,
+        ip: String,
+        country: String,
+        at: i64
+    },
+    /// The node was removed from the mesh along with its DNS records and certificate.
+    Purged {
+        node: String,
+        ip: String,
+        country: String,
+        at: i64
+    }
+}
+
+impl NodeEvent {
+    /// Event name used for the SSE `event:` field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            NodeEvent::StateChanged { .. } => "state_changed",
+            NodeEvent::HealthCheckFailed { .. } => "health_check_failed",
+            NodeEvent::InstantiationFailed { .. } => "instantiation_failed",
+            NodeEvent::Purged { .. } => "purged"
+        }
+    }
+
+    /// The node has just been moved from `from` into its current state because of `cause`.
+    pub fn state_changed(node: &Node, from: Option<&NodeState>, cause: &str) -> Self {
+        NodeEvent::StateChanged {
+            node: node.information.id.clone(),
+            ip: node.information.ip.clone(),
+            country: node.information.res.country.clone(),
+            from: from.map(|state| state.name().to_string()),
+            to: node.state.name().to_string(),
+            cause: cause.to_string(),
+            at: Utc::now().timestamp_millis()
+        }
+    }
+
+    pub fn health_check_failed(node: &Node, tries: i16) -> Self {
+        NodeEvent::HealthCheckFailed {
+            node: node.information.id.clone(),
+            ip: node.information.ip.clone(),
+            country: node.information.res.country.clone(),
+            tries,
+            at: Utc::now().timestamp_millis()
+        }
+    }
+
+    pub fn instantiation_failed(node: &Node) -> Self {
+        NodeEvent::InstantiationFailed {
+            node: node.information.id.clone(),
+            ip: node.information.ip.clone(),
+            country: node.information.res.country.clone(),
+            at: Utc::now().timestamp_millis()
+        }
+    }
+
+    pub fn purged(node: &Node) -> Self {
+        NodeEvent::Purged {
+            node: node.information.id.clone(),
+            ip: node.information.ip.clone(),
+            country: node.information.res.country.clone(),
+            at: Utc::now().timestamp_millis()
+        }
+    }
+}
+
+/// Publishes an event. Having nobody subscribed is not an error.
+pub fn publish(bus: &EventBus, event: NodeEvent) {
+    let _ = bus.send(event);
+}
@@ -1,251 +1,814 @@
 use std::time::{Duration};
-use std::{convert::Infallible};
+use std::{convert::Infallible, net::IpAddr};
 use reqwest::{Client};
 use uuid::Uuid;
 use chrono::Utc;
+use futures_timer::Delay;
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
 
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
 use warp::Reply;
-use warp::reply::{json as json_reply};
+use warp::sse::{self, Event};
+use warp::reply::{json as json_reply, with_header, with_status};
 use warp::{self, http::StatusCode};
-use crate::{Mesh};
-use crate::models::{Server, IpResponse, RegistryReturn, Node, NodeState, TaskType, Task, CloudflareDNSRecordCreate, CloudflareReturn};
-use rcgen::generate_simple_self_signed;
+use crate::Mesh;
+use crate::cloudflare::{create_dns_records, create_certificates, revoke_certificate};
+use crate::admin::Admin;
+use crate::audit::{query as query_audit, record, AuditEntry, AuditLog, AuditQuery, MESH_ACTOR};
+use crate::dead_letter;
+use crate::errors::{ErrorReply, RegistrationError};
+use crate::events::{publish, NodeEvent};
+use crate::history::{report as uptime, UptimeQuery};
+use crate::lifecycle::revive;
+use crate::metrics::{forget_node, render as render_metrics, NODES, PROVIDER_DURATION, PROVIDER_REQUESTS, REGISTRATIONS, REGISTRATION_DURATION};
+use crate::models::{IpResponse, RegistryReturn, Node, NodeState, CheckStreak, TaskType, Task, RegistrationJob, JobStatus, JobAccepted, JobPending};
+use crate::rate_limit::RegistrationPermit;
+use crate::signing::NodeRequest;
+use crate::saga::{RegistrationSaga, Compensation};
+use crate::webhooks::Delivery;
+
+/// How long a finished registration job is kept around for the node to collect.
+const JOB_RETENTION: Duration = Duration::from_secs(3600);
+
+/// How long the certificate replaced by a rotation stays valid when the node does not collect its new credentials.
+const ROTATION_GRACE: Duration = Duration::from_secs(900);
 
 pub async fn echo() -> Result<Box<dyn warp::Reply>, Infallible> {
     Ok(Box::new(StatusCode::OK))
 }
 
-/// As to properly handle the mutex, the scoped approach was taken.
-/// Prior to c793e4b94771c7f1b614d416974a580bcc0ab7e1 the non-scoped approach was taken
-/// which resulting in multiple instances of deadlocking. This new approach is
-/// not recommended and should be changed as it has one fatal flaw:
-/// - The configuration cannot be accessed for the duration of MULTIPLE async requests.
-/// Thus configuration is unable to be accessed for 10s each time a registration occurs,
-/// which if abused can cripple the service. 
+/// Accepts a registration request and returns `202 Accepted` with a job id straight away.
+/// Provisioning (location lookup, DNS records and certificate) involves several slow external requests,
+/// so it is performed in the background by `run_registration` and the node polls `registration_status`
+/// for its information. The mesh configuration is only locked for the bookkeeping on either side.
+///
+/// Every request makes several of those external requests, so the route is guarded by `limit_registrations`
+/// and the provisioning holds its `permit` until it finishes. The request has already been authenticated
+/// by `routes::registration_request` and counted against the global quota by `limit_globally`.
 pub async fn register_server(
     ip: String,
-    authentication_key: Server,
+    permit: RegistrationPermit,
+    request: NodeRequest,
     configuration: Mesh
 ) -> Result<Box<dyn Reply>, Infallible> {
-    if authentication_key.auth != configuration.lock().await.keys.check_key {
-        return Ok(Box::new(StatusCode::FORBIDDEN))
-    }
+    let config_lock = configuration.lock().await;
+    let authentication_key = request.body;
 
-    let node = {
-        let config_lock = configuration.lock().await;
+    let (address, secondary) = match parse_addresses(&ip, &authentication_key.secondary_ip) {
+        Ok(val) => val,
+        Err(err) => {
+            REGISTRATIONS.with_label_values(&["invalid"]).inc();
+            return Ok(err.into_reply())
+        }
+    };
 
-        let cloudflare_key = config_lock.keys.cloudflare_key.clone();
-        let client = config_lock.client.clone();
+    // Addresses are keyed by their canonical form, so that differently written IPv6 addresses match.
+    let ip = address.to_string();
 
-        let exists = config_lock.instance_stack.lock().await.contains_key(&ip).clone();
+    let now = Utc::now().timestamp_millis() as u128;
+    let mut jobs_lock = config_lock.jobs.lock().await;
 
-        drop(config_lock);
+    // Forget about jobs nobody has come back for.
+    jobs_lock.retain(|_, job| now.saturating_sub(job.created_at) < JOB_RETENTION.as_millis());
 
-        match exists {
-            true => {
-                configuration.lock().await.instance_stack.lock().await.get_mut(&ip).cloned()
-            },
-            false => {
-                let id = Uuid::new_v4();
-                let location = match get_location(&client, &ip).await {
-                    Ok(val) => val,
-                    Err(err) => {
-                        return Ok(Box::new(err))
-                    }
-                };
-
-                println!("[location]: {:?}", &location);
-                println!("[key]: {}", &cloudflare_key);
-            
-                let identifier = format!("{}-{}", &location.country.to_lowercase().replace(" ", "-"), id.to_string());
-    
-                let record = match create_dns_records(&cloudflare_key, &client, &identifier, &ip, true).await {
-                    Ok(val) => val,
-                    Err(err) => {
-                        return Ok(Box::new(err))
-                    },
-                };
-
-                let dns_record = match create_dns_records(&cloudflare_key, &client, &format!("{}.dns", &identifier.to_string()), &ip, false).await {
-                    Ok(val) => val,
-                    Err(err) => {
-                        return Ok(Box::new(err))
-                    },
-                };
-            
-                let (cert, key, cert_id) = match create_certificates(&cloudflare_key, &client, &identifier).await {
-                    Ok(val) => val,
-                    Err(err) => {
-                        return Ok(Box::new(err))
-                    }
-                };
-    
-                let rr = RegistryReturn {
-                    cert, key, ip,
-                    record_id: record.result.id, record_dns_id: dns_record.result.id, cert_id,
-                    id: identifier.to_string(), res: location
-                };
-    
-                Some(Node {
-                    information: rr.clone(),
-                    state: NodeState::Registering
-                })
-            },
-        }
+    // A registration for this address is already underway, hand back the same job.
+    // Job ids are never logged or audited, as they lead to the node's key once the job completes.
+    if let Some((job_id, _)) = jobs_lock.iter().find(|(_, job)| job.ip == ip && matches!(job.status, JobStatus::Pending)) {
+        return Ok(Box::new(job_accepted(job_id)))
+    }
+
+    let job_id = Uuid::new_v4().to_string();
+    let mut stack_lock = config_lock.instance_stack.lock().await;
+
+    // Dual-stack nodes are stored under their primary address, but may re-register using either.
+    let ip = match stack_lock.iter().find(|(_, n)| n.information.secondary_ip.as_ref() == Some(&ip)) {
+        Some((primary, _)) => primary.clone(),
+        None => ip
     };
 
-    let res: Box<(dyn Reply + 'static)> = match node {
+    match stack_lock.get_mut(&ip) {
         Some(n) => {
-            let config_lock = configuration.lock().await;
+            // The node is already known to the mesh, what happens depends on where it is in its lifecycle:
+            // - Online or Degraded, it is published and being monitored; nothing needs to be done.
+            // - Registering, an Instantiate task is already queued and will publish it.
+            // - Draining, a Dismiss is queued; the node may re-register once it is Offline.
+            // - Offline, it was dismissed and a Purge is counting down. It is given a new generation
+            //   (which cancels the Purge and any stale tasks) and instantiated again, unless it has
+            //   been revived too often already, in which case it is quarantined until the Purge.
+            // - Failed or Quarantined, its resources are about to be removed.
+            // Draining, Failed and Quarantined nodes are refused, their credentials are stale or soon will be.
+            if n.state == NodeState::Offline && revive(n, "re-registered after dismissal", &config_lock.events).unwrap_or(false) {
+                let exec_time = now + Duration::new(30, 0).as_millis();
 
-            config_lock.instance_stack.lock().await.insert(n.information.ip.clone(), n.clone());
+                config_lock.task_queue.lock().await.push_back(Task {
+                    task_type: TaskType::Instantiate(0),
+                    // Handing over lookup information 
+                    action_object: n.information.ip.to_string(),
+                    exec_at: exec_time,
+                    generation: n.generation,
+                    last_error: None
+                });
+            }
 
-            let exec_time = Utc::now().timestamp_millis() as u128 + Duration::new(30, 0).as_millis();
+            if matches!(n.state, NodeState::Draining | NodeState::Failed | NodeState::Quarantined) {
+                info!(ip = %ip, node = %n.information.id, state = n.state.name(), "Refusing registration of unavailable node");
+                REGISTRATIONS.with_label_values(&["conflict"]).inc();
 
-            config_lock.task_queue.lock().await.push_back(Task {
-                task_type: TaskType::Instantiate(0),
-                // Handing over lookup information 
-                action_object: n.information.ip.to_string(),
-                exec_at: exec_time
+                return Ok(RegistrationError::NodeUnavailable(n.state.clone()).into_reply())
+            }
+
+            if authentication_key.rotate {
+                jobs_lock.insert(job_id.clone(), RegistrationJob {
+                    ip: ip.clone(),
+                    status: JobStatus::Pending,
+                    created_at: now,
+                    superseded: None
+                });
+
+                let span = info_span!("rotation", ip = %ip, node = %n.information.id);
+                let rotation = run_rotation(job_id.clone(), ip.clone(), configuration.clone());
+
+                tokio::spawn(async move {
+                    rotation.await;
+                    drop(permit);
+                }.instrument(span));
+            }else {
+                jobs_lock.insert(job_id.clone(), RegistrationJob {
+                    ip: ip.clone(),
+                    status: JobStatus::Complete(Box::new(n.information.clone())),
+                    created_at: now,
+                    superseded: None
+                });
+            }
+        },
+        None => {
+            jobs_lock.insert(job_id.clone(), RegistrationJob {
+                ip: ip.clone(),
+                status: JobStatus::Pending,
+                created_at: now,
+                superseded: None
             });
 
-            println!("Task Queue: {:?}", &config_lock.task_queue);
+            let span = info_span!("registration", ip = %address, node = field::Empty);
+            let registration = run_registration(job_id.clone(), address, secondary, configuration.clone());
+
+            tokio::spawn(async move {
+                registration.await;
+                drop(permit);
+            }.instrument(span));
+        }
+    }
+
+    REGISTRATIONS.with_label_values(&["accepted"]).inc();
 
-            let reply = json_reply(&n.clone().information);
+    record(&config_lock.audit, AuditEntry {
+        ip: Some(ip.clone()),
+        ..AuditEntry::new(&node_actor(&ip), "registration_requested", String::from(if authentication_key.rotate { "rotating credentials" } else { "" }))
+    });
 
-            drop(config_lock);
-            drop(n);
+    Ok(Box::new(job_accepted(&job_id)))
+}
 
-            Box::new(reply)
+/// Reports the progress of a registration job. Whilst provisioning is running this is `202 Accepted`,
+/// once complete the node receives its `RegistryReturn`, exactly as registration used to return inline.
+/// Authenticated like `register_server`, or with the certificate of the node being rotated over mutual TLS.
+///
+/// The details are handed out once, after which the key material is dropped from the job and further polls are
+/// answered with `410 Gone`. A node which lost them re-registers, as it would after a restart.
+pub async fn registration_status(
+    job_id: String,
+    request: NodeRequest,
+    configuration: Mesh
+) -> Result<Box<dyn Reply>, Infallible> {
+    let config_lock = configuration.lock().await;
+    let mut jobs_lock = config_lock.jobs.lock().await;
+
+    let certified = match jobs_lock.get(&job_id) {
+        Some(job) => config_lock.instance_stack.lock().await.get(&job.ip).map(|node| request.certifies(node)).unwrap_or(false),
+        None => false
+    };
+
+    if !certified && !request.authorized(&config_lock.keys.check_key) {
+        return Ok(Box::new(StatusCode::FORBIDDEN))
+    }
+
+    let job = match jobs_lock.get_mut(&job_id) {
+        Some(job) => job,
+        None => return Ok(Box::new(StatusCode::NOT_FOUND))
+    };
+
+    let res: Box<dyn Reply> = match &job.status {
+        JobStatus::Pending => {
+            Box::new(with_status(json_reply(&JobPending {
+                job_id,
+                status: "pending".to_string()
+            }), StatusCode::ACCEPTED))
         },
-        None => {
-            Box::new(StatusCode::INTERNAL_SERVER_ERROR)
+        JobStatus::Complete(_) => match std::mem::replace(&mut job.status, JobStatus::Collected) {
+            JobStatus::Complete(information) => {
+                // The node has its new credentials, so the ones they replace can go.
+                if let Some(cert_id) = job.superseded.take() {
+                    let revocation = revoke_superseded(
+                        config_lock.keys.cloudflare_key.clone(),
+                        config_lock.client.clone(),
+                        config_lock.audit.clone(),
+                        information.id.clone(),
+                        job.ip.clone(),
+                        cert_id
+                    );
+
+                    tokio::spawn(revocation.instrument(Span::current()));
+                }
+
+                Box::new(json_reply(&information))
+            },
+            _ => unreachable!()
         },
+        JobStatus::Failed(err) => err.clone().into_reply(),
+        JobStatus::Collected => Box::new(with_status(json_reply(&ErrorReply::new(
+            "job_collected",
+            "the registration details have already been collected, register again to receive them".to_string()
+        )), StatusCode::GONE))
     };
-    
+
     Ok(res)
 }
 
-async fn create_dns_records(
-    cloudflare_key: &String,
-    client: &Client,
-    identifier: &String,
-    ip: &String,
-    proxied: bool
-) -> Result<CloudflareDNSRecordCreate, StatusCode> {
-    let response = match client.post("https://api.cloudflare.com/client/v4/zones/ebb52f1687a35641237774c39391ba2a/dns_records")
-        .body(format!("
-        {{
-            \"type\": \"A\",
-            \"name\": \"{}\",
-            \"content\": \"{}\",
-            \"ttl\": 3600,
-            \"priority\": 10,
-            \"proxied\": {}
-        }}", identifier, ip, proxied))
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}",  cloudflare_key))
-        .send().await {
-            Ok(response) => {
-                match response.json::<CloudflareDNSRecordCreate>().await {
-                    Ok(r) => Ok(r),
-                    Err(err) => {
-                        println!("[err]: Deserializing Cloudflare Result: {}", err);
-                        return Err(StatusCode::INTERNAL_SERVER_ERROR)
-                    },
-                }
-            },
-            Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
-        };
+/// Renders the mesh metrics in the Prometheus text format.
+/// Node counts are derived from the instance stack at scrape time rather than tracked on every transition.
+pub async fn metrics(
+    configuration: Mesh
+) -> Result<Box<dyn Reply>, Infallible> {
+    {
+        let config_lock = configuration.lock().await;
+        let stack_lock = config_lock.instance_stack.lock().await;
 
-    response
+        for state in NodeState::ALL.iter() {
+            let count = stack_lock.values().filter(|n| &n.state == state).count();
+            NODES.with_label_values(&[state.name()]).set(count as i64);
+        }
+    }
+
+    match render_metrics() {
+        Ok(body) => Ok(Box::new(with_header(body, "Content-Type", "text/plain; version=0.0.4"))),
+        Err(err) => {
+            error!(error = %err, "Unable to render metrics");
+            Ok(Box::new(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
 }
 
-async fn create_certificates(
-    cloudflare_key: &String,
-    client: &Client,
-    id: &String
-) -> Result<(String, String, String), StatusCode> {
-    let cert = match generate_simple_self_signed(vec![format!("{}.reseda.app", id.to_string())]) {
-        Ok(r) => r,
+/// Streams node lifecycle events as Server-Sent Events, for the dashboard and client API to react to
+/// nodes joining and leaving without polling the `Server` table. A subscriber which falls too far behind
+/// skips the events it missed rather than stalling the mesh.
+pub async fn events(
+    configuration: Mesh
+) -> Result<Box<dyn Reply>, Infallible> {
+    let receiver = configuration.lock().await.events.subscribe();
+
+    let stream = BroadcastStream::new(receiver).filter_map(|event| match event {
+        Ok(event) => Event::default()
+            .event(event.name())
+            .json_data(&event)
+            .ok()
+            .map(Ok::<Event, Infallible>),
+        Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+            warn!(skipped, "Event subscriber lagged behind, events dropped");
+            None
+        }
+    });
+
+    Ok(Box::new(sse::reply(sse::keep_alive().stream(stream))))
+}
+
+/// Lists the most recent webhook deliveries, newest first.
+pub async fn webhook_deliveries(
+    admin: Admin,
+    configuration: Mesh
+) -> Result<Box<dyn Reply>, Infallible> {
+    let config_lock = configuration.lock().await;
+
+    record(&config_lock.audit, AuditEntry::new(&admin.actor(), "webhook_deliveries_viewed", String::new()));
+
+    let deliveries = config_lock.webhook_deliveries.lock().await
+        .iter()
+        .rev()
+        .cloned()
+        .collect::<Vec<Delivery>>();
+
+    Ok(Box::new(json_reply(&deliveries)))
+}
+
+/// Queries the audit log, optionally filtered by node and time range.
+pub async fn audit_log(
+    query: AuditQuery,
+    admin: Admin,
+    configuration: Mesh
+) -> Result<Box<dyn Reply>, Infallible> {
+    let config_lock = configuration.lock().await;
+
+    record(&config_lock.audit, AuditEntry::new(&admin.actor(), "audit_queried", format!("{:?}", query)));
+
+    let pool = config_lock.pool.clone();
+    drop(config_lock);
+
+    match query_audit(&pool, &query).await {
+        Ok(records) => Ok(Box::new(json_reply(&records))),
         Err(err) => {
-            println!("[err]: Deserializing Certificate: {}", err);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR)
-        },
+            error!(error = %err, "Unable to query audit log");
+            Ok(Box::new(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+/// Reports node availability and health check success ratios per node and per country,
+/// computed from the recorded state history.
+pub async fn uptime_report(
+    query: UptimeQuery,
+    admin: Admin,
+    configuration: Mesh
+) -> Result<Box<dyn Reply>, Infallible> {
+    let config_lock = configuration.lock().await;
+
+    record(&config_lock.audit, AuditEntry::new(&admin.actor(), "uptime_queried", format!("{:?}", query)));
+
+    let pool = config_lock.pool.clone();
+    drop(config_lock);
+
+    match uptime(&pool, &query).await {
+        Ok(report) => Ok(Box::new(json_reply(&report))),
+        Err(err) => {
+            error!(error = %err, "Unable to compute uptime report");
+            Ok(Box::new(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+/// Lists the dead letters, the most recent first.
+pub async fn dead_letters(
+    admin: Admin,
+    configuration: Mesh
+) -> Result<Box<dyn Reply>, Infallible> {
+    let config_lock = configuration.lock().await;
+
+    record(&config_lock.audit, AuditEntry::new(&admin.actor(), "dead_letters_viewed", String::new()));
+
+    let pool = config_lock.pool.clone();
+    drop(config_lock);
+
+    match dead_letter::list(&pool).await {
+        Ok(letters) => Ok(Box::new(json_reply(&letters))),
+        Err(err) => {
+            error!(error = %err, "Unable to list dead letters");
+            Ok(Box::new(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+/// Queues a dead-lettered task again with a fresh retry budget, removing it from the dead letters.
+/// Only possible whilst the node is still in the generation the task was queued for; a node which has since
+/// re-registered or been purged, or which the mesh has forgotten by restarting, answers `409 Conflict` and the letter is kept.
+///
+/// A dead-lettered Instantiate leaves its node Failed until its Purge. Re-driving it instantiates the node again
+/// under a new generation, which supersedes that Purge.
+pub async fn redrive_dead_letter(
+    letter_id: String,
+    admin: Admin,
+    configuration: Mesh
+) -> Result<Box<dyn Reply>, Infallible> {
+    let config_lock = configuration.lock().await;
+
+    let letter = match dead_letter::find(&config_lock.pool, &letter_id).await {
+        Ok(Some(letter)) => letter,
+        Ok(None) => return Ok(Box::new(StatusCode::NOT_FOUND)),
+        Err(err) => {
+            error!(letter = %letter_id, error = %err, "Unable to read dead letter");
+            return Ok(Box::new(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    };
+
+    let mut task = match letter.redrive() {
+        Some(task) => task,
+        None => return Ok(Box::new(StatusCode::UNPROCESSABLE_ENTITY))
+    };
+
+    let reinstantiate = matches!(task.task_type, TaskType::Instantiate(_));
+    let mut stack_lock = config_lock.instance_stack.lock().await;
+
+    let node = match stack_lock.get_mut(&letter.ip) {
+        Some(node) if node.generation == task.generation && (!reinstantiate || node.state == NodeState::Failed) => node,
+        _ => return Ok(Box::new(with_status(json_reply(&ErrorReply::new(
+            "node_superseded",
+            "the node has re-registered or left the mesh since the task was dead-lettered".to_string()
+        )), StatusCode::CONFLICT)))
     };
 
-    let cert_public = match cert.serialize_request_pem() {
-        Ok(r) => r,
+    match dead_letter::remove(&config_lock.pool, &letter.id).await {
+        Ok(true) => {},
+        // Re-driven by someone else in the meantime.
+        Ok(false) => return Ok(Box::new(StatusCode::NOT_FOUND)),
         Err(err) => {
-            println!("[err]: Deserializing Certificate: {}", err);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR)
+            error!(letter = %letter.id, error = %err, "Unable to remove dead letter");
+            return Ok(Box::new(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+
+    if reinstantiate {
+        if let Ok(true) = revive(node, "instantiation re-driven", &config_lock.events) {
+            task.generation = node.generation;
+        }
+    }
+
+    info!(letter = %letter.id, task = %letter.task, ip = %letter.ip, "Re-driving dead-lettered task");
+
+    record(&config_lock.audit, AuditEntry {
+        node_id: letter.node.clone(),
+        ip: Some(letter.ip.clone()),
+        ..AuditEntry::new(&admin.actor(), "task_redriven", format!("{} {}", letter.task, letter.id))
+    });
+
+    config_lock.task_queue.lock().await.push_back(task);
+
+    Ok(Box::new(with_status(json_reply(&letter), StatusCode::ACCEPTED)))
+}
+
+/// Identity recorded in the audit log for actions performed by a node.
+fn node_actor(ip: &String) -> String {
+    format!("node@{}", ip)
+}
+
+fn job_accepted(job_id: &String) -> impl Reply {
+    with_status(json_reply(&JobAccepted {
+        job_id: job_id.clone(),
+        status_url: format!("/register/jobs/{}", job_id)
+    }), StatusCode::ACCEPTED)
+}
+
+/// Background half of `register_server`. Provisions the node and records the outcome on its job,
+/// rolling back any created resources should a step fail.
+async fn run_registration(
+    job_id: String,
+    ip: IpAddr,
+    secondary_ip: Option<IpAddr>,
+    configuration: Mesh
+) {
+    let (cloudflare_key, client, audit) = {
+        let config_lock = configuration.lock().await;
+
+        (config_lock.keys.cloudflare_key.clone(), config_lock.client.clone(), config_lock.audit.clone())
+    };
+
+    let mut saga = RegistrationSaga::new(ip.to_string(), audit.clone());
+    let timer = REGISTRATION_DURATION.start_timer();
+
+    let status = match provision_node(&cloudflare_key, &client, &ip, secondary_ip.as_ref(), &mut saga).await {
+        Ok(rr) => {
+            let n = Node {
+                information: rr,
+                state: NodeState::Registering,
+                generation: 0,
+                checks: CheckStreak::default(),
+                purged: vec![]
+            };
+
+            let config_lock = configuration.lock().await;
+
+            // A registration which raced this one for the same address is replaced, along with its series.
+            if let Some(replaced) = config_lock.instance_stack.lock().await.insert(n.information.ip.clone(), n.clone()) {
+                forget_node(&replaced.information.id, &replaced.information.res.country);
+            }
+            publish(&config_lock.events, NodeEvent::state_changed(&n, None, "registered"));
+
+            let exec_time = Utc::now().timestamp_millis() as u128 + Duration::new(30, 0).as_millis();
+
+            config_lock.task_queue.lock().await.push_back(Task {
+                task_type: TaskType::Instantiate(0),
+                // Handing over lookup information 
+                action_object: n.information.ip.to_string(),
+                exec_at: exec_time,
+                generation: n.generation,
+                last_error: None
+            });
+
+            info!(node = %n.information.id, "Registration provisioned, instantiating in 30s");
+
+            timer.observe_duration();
+            REGISTRATIONS.with_label_values(&["completed"]).inc();
+
+            JobStatus::Complete(Box::new(n.information))
         },
+        Err(err) => {
+            warn!(code = err.code(), error = %err, "Registration failed, rolling back");
+            timer.observe_duration();
+            REGISTRATIONS.with_label_values(&["failed"]).inc();
+
+            record(&audit, AuditEntry {
+                ip: Some(ip.to_string()),
+                ..AuditEntry::new(&node_actor(&ip.to_string()), "registration_failed", format!("[{}] {}", err.code(), err))
+            });
+
+            saga.rollback(&cloudflare_key, &client).await;
+
+            JobStatus::Failed(err)
+        }
     };
 
-    let cert_private = cert.serialize_private_key_pem();
-    let cert_string = cert_public.replace("\r", "").split("\n").collect::<Vec<&str>>().join("\\n");
-    
-    let (cert, key, id) = match client.post("https://api.cloudflare.com/client/v4/certificates")
-        .body(format!("
-        {{
-            \"hostnames\": [
-                \"{}.reseda.app\"
-            ],
-            \"requested_validity\": 5475,
-            \"request_type\": \"origin-rsa\",
-            \"csr\": \"{}\"
-        }}", id, cert_string))
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", cloudflare_key))
-        .send().await {
-            Ok(response) => {
-                let r = response.json::<CloudflareReturn>().await;
-
-                match r {
-                    Ok(return_value) => {
-                        if return_value.success == false {
-                            println!("[err]: cloudflare certificate creation FAILED, return value FAILURE. Reason: {:?}", return_value);
-                        }
-
-                        (return_value.result.certificate, cert_private, return_value.result.id)
-                    },
-                    Err(err) => {
-                        println!("[err]: Deserializing Cloudflare Result: {}", err);
-                        return Err(StatusCode::INTERNAL_SERVER_ERROR)
-                    }
-                }
-            },
-            Err(err) => {
-                println!("[err]: Error in setting proxied DNS {}", err);
-                return Err(StatusCode::INTERNAL_SERVER_ERROR)
-            },
-        };
+    let config_lock = configuration.lock().await;
+    let mut jobs_lock = config_lock.jobs.lock().await;
 
-    Ok((cert, key, id))
+    if let Some(job) = jobs_lock.get_mut(&job_id) {
+        job.status = status;
+    }
 }
 
 async fn get_location(
     client: &Client,
     ip: &String
-) -> Result<IpResponse, StatusCode> {
+) -> Result<IpResponse, RegistrationError> {
+    let timer = PROVIDER_DURATION.with_label_values(&["location_lookup"]).start_timer();
+    let location = lookup_location(client, ip).await;
+
+    timer.observe_duration();
+    PROVIDER_REQUESTS.with_label_values(&["location_lookup", if location.is_ok() { "success" } else { "failure" }]).inc();
+
+    location
+}
+
+async fn lookup_location(
+    client: &Client,
+    ip: &String
+) -> Result<IpResponse, RegistrationError> {
     let data = match client.get(format!("http://ip-api.com/json/{}", ip))
         .send().await {
             Ok(data) => data,
-            Err(_) => {
-                return Err(StatusCode::INTERNAL_SERVER_ERROR)
+            Err(err) => {
+                return Err(RegistrationError::Location(err.to_string()))
             },
         };
 
     if !data.status().is_success() {
-        return Err(StatusCode::INTERNAL_SERVER_ERROR)
+        return Err(RegistrationError::Location(format!("ip-api responded with {}", data.status())))
     }
 
     let val = match data.json::<IpResponse>().await {
         Ok(val) => val,
-        Err(_) => {
-            return Err(StatusCode::INTERNAL_SERVER_ERROR)
+        Err(err) => {
+            return Err(RegistrationError::Location(err.to_string()))
         }
     };
 
     Ok(val)
 }
+
+/// Background half of a re-registration requesting new credentials. A new certificate is issued
+/// for the node's identifier and swapped in. The previous certificate is revoked once the node has collected
+/// the new one from `registration_status`, or after `ROTATION_GRACE` should it never do so.
+async fn run_rotation(
+    job_id: String,
+    ip: String,
+    configuration: Mesh
+) {
+    let (cloudflare_key, client, audit, current) = {
+        let config_lock = configuration.lock().await;
+        let current = config_lock.instance_stack.lock().await.get(&ip).cloned();
+
+        (config_lock.keys.cloudflare_key.clone(), config_lock.client.clone(), config_lock.audit.clone(), current)
+    };
+
+    let current = match current {
+        Some(n) => n.information,
+        None => return
+    };
+
+    let status = match create_certificates(&cloudflare_key, &client, &current.id).await {
+        Ok((cert, key, cert_id)) => {
+            let config_lock = configuration.lock().await;
+            let mut stack_lock = config_lock.instance_stack.lock().await;
+
+            match stack_lock.get_mut(&ip) {
+                Some(n) => {
+                    n.information.cert = cert;
+                    n.information.key = key;
+                    n.information.cert_id = cert_id;
+
+                    let information = n.information.clone();
+
+                    drop(stack_lock);
+                    drop(config_lock);
+
+                    // The node is still using the superseded certificate until it collects the new one, see `registration_status`.
+                    info!(certificate = %current.cert_id, "Rotated credentials, superseded certificate is revoked once they are collected");
+                    record(&audit, AuditEntry::for_node(&node_actor(&ip), "resource_created", &information, format!("certificate {}, credentials rotated", information.cert_id)));
+
+                    JobStatus::Complete(Box::new(information))
+                },
+                None => {
+                    // The node was purged whilst the certificate was being issued, it is of no use to anyone.
+                    drop(stack_lock);
+                    drop(config_lock);
+
+                    if let Err(err) = revoke_certificate(&cloudflare_key, &client, &cert_id).await {
+                        error!(certificate = %cert_id, error = %err, "Unable to revoke orphaned certificate");
+                        record(&audit, AuditEntry::for_node(MESH_ACTOR, "resource_delete_failed", &current, format!("certificate {}, {}", cert_id, err)));
+                    }
+
+                    return
+                }
+            }
+        },
+        Err(err) => {
+            warn!(code = err.code(), error = %err, "Credential rotation failed");
+            JobStatus::Failed(err)
+        }
+    };
+
+    let rotated = matches!(status, JobStatus::Complete(_));
+
+    {
+        let config_lock = configuration.lock().await;
+        let mut jobs_lock = config_lock.jobs.lock().await;
+
+        if let Some(job) = jobs_lock.get_mut(&job_id) {
+            job.status = status;
+
+            if rotated {
+                job.superseded = Some(current.cert_id.clone());
+            }
+        }
+    }
+
+    if !rotated {
+        return
+    }
+
+    // A node which never collects its new credentials keeps the superseded certificate for the grace period only.
+    // Waited out separately, the registration permit is released once this returns.
+    let grace = async move {
+        Delay::new(ROTATION_GRACE).await;
+
+        let superseded = {
+            let config_lock = configuration.lock().await;
+            let mut jobs_lock = config_lock.jobs.lock().await;
+
+            jobs_lock.get_mut(&job_id).and_then(|job| job.superseded.take())
+        };
+
+        if let Some(cert_id) = superseded {
+            warn!(certificate = %cert_id, "Rotated credentials were not collected, revoking superseded certificate");
+            revoke_superseded(cloudflare_key, client, audit, current.id, ip, cert_id).await;
+        }
+    };
+
+    tokio::spawn(grace.instrument(Span::current()));
+}
+
+/// Revokes the certificate replaced by a credential rotation.
+async fn revoke_superseded(
+    cloudflare_key: String,
+    client: Client,
+    audit: AuditLog,
+    node_id: String,
+    ip: String,
+    cert_id: String
+) {
+    let entry = |action: &str, detail: String| AuditEntry {
+        node_id: Some(node_id.clone()),
+        ip: Some(ip.clone()),
+        ..AuditEntry::new(MESH_ACTOR, action, detail)
+    };
+
+    match revoke_certificate(&cloudflare_key, &client, &cert_id).await {
+        Ok(_) => {
+            info!(certificate = %cert_id, "Revoked superseded certificate");
+            record(&audit, entry("resource_deleted", format!("certificate {}, superseded by rotation", cert_id)));
+        },
+        Err(err) => {
+            error!(certificate = %cert_id, error = %err, "Unable to revoke superseded certificate");
+            record(&audit, entry("resource_delete_failed", format!("certificate {}, {}", cert_id, err)));
+        }
+    }
+}
+
+/// Creates the DNS records and certificate for a newly registering node.
+/// Each created resource is recorded on the `saga` so the caller can roll back on failure.
+async fn provision_node(
+    cloudflare_key: &String,
+    client: &Client,
+    ip: &IpAddr,
+    secondary_ip: Option<&IpAddr>,
+    saga: &mut RegistrationSaga
+) -> Result<RegistryReturn, RegistrationError> {
+    let id = Uuid::new_v4();
+    let location = get_location(client, &ip.to_string()).await?;
+
+    let identifier = format!("{}-{}", &location.country.to_lowercase().replace(" ", "-"), id);
+    saga.identify(&identifier);
+
+    Span::current().record("node", identifier.as_str());
+    info!(country = %location.country, city = %location.city, "Resolved node location");
+    let dns_identifier = format!("{}.dns", &identifier.to_string());
+
+    let record = create_dns_records(cloudflare_key, client, &identifier, ip, true).await?;
+    saga.completed(Compensation::DeleteDnsRecord(record.id.clone()));
+
+    let dns_record = create_dns_records(cloudflare_key, client, &dns_identifier, ip, false).await?;
+    saga.completed(Compensation::DeleteDnsRecord(dns_record.id.clone()));
+
+    // A dual-stack node receives the counterpart (A or AAAA) of both records under the same names.
+    let (secondary_record_id, secondary_record_dns_id) = match secondary_ip {
+        Some(secondary) => {
+            let record = create_dns_records(cloudflare_key, client, &identifier, secondary, true).await?;
+            saga.completed(Compensation::DeleteDnsRecord(record.id.clone()));
+
+            let dns_record = create_dns_records(cloudflare_key, client, &dns_identifier, secondary, false).await?;
+            saga.completed(Compensation::DeleteDnsRecord(dns_record.id.clone()));
+
+            (Some(record.id), Some(dns_record.id))
+        },
+        None => (None, None)
+    };
+
+    let (cert, key, cert_id) = create_certificates(cloudflare_key, client, &identifier).await?;
+    saga.completed(Compensation::RevokeCertificate(cert_id.clone()));
+
+    Ok(RegistryReturn {
+        cert, key, ip: ip.to_string(),
+        record_id: record.id, record_dns_id: dns_record.id, cert_id,
+        id: identifier.to_string(), res: location,
+        secondary_ip: secondary_ip.map(|secondary| secondary.to_string()),
+        secondary_record_id, secondary_record_dns_id
+    })
+}
+
+/// Parses the registering address and the optional second address of a dual-stack node.
+fn parse_addresses(
+    ip: &String,
+    secondary_ip: &Option<String>
+) -> Result<(IpAddr, Option<IpAddr>), RegistrationError> {
+    let address = match ip.parse::<IpAddr>() {
+        Ok(val) => val,
+        Err(_) => {
+            return Err(RegistrationError::InvalidAddress(format!("{} is not an IPv4 or IPv6 address", ip)))
+        }
+    };
+
+    let secondary = match secondary_ip {
+        Some(secondary_ip) => match secondary_ip.parse::<IpAddr>() {
+            Ok(val) => Some(val),
+            Err(_) => {
+                return Err(RegistrationError::InvalidAddress(format!("{} is not an IPv4 or IPv6 address", secondary_ip)))
+            }
+        },
+        None => None
+    };
+
+    match secondary {
+        Some(secondary) if secondary.is_ipv4() == address.is_ipv4() => {
+            Err(RegistrationError::InvalidAddress(format!("{} and {} are of the same address family, a dual-stack node must provide one IPv4 and one IPv6 address", address, secondary)))
+        },
+        _ => Ok((address, secondary))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(ip: &str, secondary_ip: Option<&str>) -> Result<(IpAddr, Option<IpAddr>), RegistrationError> {
+        parse_addresses(&ip.to_string(), &secondary_ip.map(|ip| ip.to_string()))
+    }
+
+    #[test]
+    fn parses_single_and_dual_stack_addresses() {
+        let (address, secondary) = parse("10.0.0.1", None).unwrap();
+        assert_eq!(address.to_string(), "10.0.0.1");
+        assert_eq!(secondary, None);
+
+        let (address, secondary) = parse("10.0.0.1", Some("2001:db8::1")).unwrap();
+        assert!(address.is_ipv4());
+        assert_eq!(secondary.unwrap().to_string(), "2001:db8::1");
+    }
+
+    #[test]
+    fn canonicalises_ipv6_addresses() {
+        let (address, secondary) = parse("2001:0DB8:0000:0000:0000:0000:0000:0001", Some("10.0.0.1")).unwrap();
+
+        assert_eq!(address.to_string(), "2001:db8::1");
+        assert_eq!(secondary.unwrap().to_string(), "10.0.0.1");
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        for (ip, secondary_ip) in [("not an ip", None), ("10.0.0.256", None), ("10.0.0.1", Some("2001:db8::g"))] {
+            assert!(matches!(parse(ip, secondary_ip), Err(RegistrationError::InvalidAddress(_))), "{} {:?}", ip, secondary_ip);
+        }
+    }
+
+    #[test]
+    fn rejects_a_secondary_of_the_same_family() {
+        assert!(matches!(parse("10.0.0.1", Some("10.0.0.2")), Err(RegistrationError::InvalidAddress(_))));
+        assert!(matches!(parse("2001:db8::1", Some("2001:db8::2")), Err(RegistrationError::InvalidAddress(_))));
+    }
+}
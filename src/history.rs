@@ -0,0 +1,414 @@
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use futures_timer::Delay;
+use serde::{Deserialize, Serialize};
+use sqlx::{Any, Pool};
+use tokio::sync::Mutex;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::error;
+
+use crate::dialect::placeholders;
+use crate::events::{EventBus, NodeEvent};
+use crate::models::{Node, NodeState};
+
+/// How often the in-memory health check counts are written to the `NodeHealthRollup` table.
+const ROLLUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Window covered by an uptime report when no `from` is given.
+const DEFAULT_REPORT_WINDOW: i64 = 7 * 24 * 60 * 60 * 1000;
+
+/// State recorded once a node has left the mesh, either purged or failed to instantiate.
+/// Time after it does not count towards the node's observed time.
+pub const REMOVED: &str = "removed";
+
+/// Health check outcomes of a node since the last rollup.
+#[derive(Debug, Clone, Default)]
+pub struct HealthCount {
+    pub country: String,
+    pub successes: i64,
+    pub failures: i64
+}
+
+/// Health check outcomes per node id, flushed to the database every `ROLLUP_INTERVAL`.
+pub type HealthTally = Arc<Mutex<HashMap<String, HealthCount>>>;
+
+/// Counts the outcome of a health check against `node`.
+pub async fn count_health_check(tally: &HealthTally, node: &Node, success: bool) {
+    let mut tally_lock = tally.lock().await;
+    let count = tally_lock.entry(node.information.id.clone()).or_insert_with(|| HealthCount {
+        country: node.information.res.country.clone(),
+        ..HealthCount::default()
+    });
+
+    if success {
+        count.successes += 1;
+    }else {
+        count.failures += 1;
+    }
+}
+
+/// Starts the background tasks which record every state transition published on the event bus
+/// into `NodeStateHistory`, and periodically roll the health check counts up into `NodeHealthRollup`.
+pub fn spawn_recorder(pool: Pool<Any>, bus: &EventBus) -> HealthTally {
+    let tally: HealthTally = Arc::new(Mutex::new(HashMap::new()));
+    let mut events = bus.subscribe();
+    let history_pool = pool.clone();
+
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    error!(skipped, "State history recorder lagged behind, transitions were not recorded");
+                    continue;
+                },
+                Err(RecvError::Closed) => return
+            };
+
+            let (node, ip, country, state, cause, at) = match event {
+                NodeEvent::StateChanged { node, ip, country, to, cause, at, .. } => (node, ip, country, to, cause, at),
+                NodeEvent::InstantiationFailed { node, ip, country, at } => {
+                    (node, ip, country, REMOVED.to_string(), "node never became healthy".to_string(), at)
+                },
+                NodeEvent::Purged { node, ip, country, at } => {
+                    (node, ip, country, REMOVED.to_string(), "purged".to_string(), at)
+                },
+                NodeEvent::HealthCheckFailed { .. } => continue
+            };
+
+            let result = sqlx::query(&placeholders(&history_pool, "insert into NodeStateHistory (node_id, ip, country, state, cause, at) values (?, ?, ?, ?, ?, ?)"))
+                .bind(&node)
+                .bind(&ip)
+                .bind(&country)
+                .bind(&state)
+                .bind(&cause)
+                .bind(at)
+                .execute(&history_pool)
+                .await;
+
+            if let Err(err) = result {
+                error!(node = %node, state = %state, error = %err, "Unable to record state transition");
+            }
+        }
+    });
+
+    let rollup_tally = tally.clone();
+
+    tokio::spawn(async move {
+        let mut window_start = Utc::now().timestamp_millis();
+
+        loop {
+            Delay::new(ROLLUP_INTERVAL).await;
+
+            let window_end = Utc::now().timestamp_millis();
+            let counts = mem::take(&mut *rollup_tally.lock().await);
+
+            for (node, count) in counts {
+                let result = sqlx::query(&placeholders(&pool, "insert into NodeHealthRollup (node_id, country, window_start, window_end, successes, failures) values (?, ?, ?, ?, ?, ?)"))
+                    .bind(&node)
+                    .bind(&count.country)
+                    .bind(window_start)
+                    .bind(window_end)
+                    .bind(count.successes)
+                    .bind(count.failures)
+                    .execute(&pool)
+                    .await;
+
+                if let Err(err) = result {
+                    error!(node = %node, counts = ?count, error = %err, "Unable to write health check rollup");
+                }
+            }
+
+            window_start = window_end;
+        }
+    });
+
+    tally
+}
+
+/// Filters accepted by `GET /admin/uptime`. Times are unix milliseconds, the window defaults to the last seven days.
+#[derive(Deserialize, Debug, Default)]
+pub struct UptimeQuery {
+    pub node: Option<String>,
+    pub country: Option<String>,
+    pub from: Option<i64>,
+    pub to: Option<i64>
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct Transition {
+    node_id: String,
+    country: String,
+    state: String,
+    at: i64
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct Rollup {
+    node_id: String,
+    successes: i64,
+    failures: i64
+}
+
+/// Availability of a single node over the report window.
+///
+/// `availability` is the share of the time the node was part of the mesh that it spent online,
+/// and is absent if the node was not part of the mesh during the window at all. Time spent degraded is not online,
+/// as the node is hidden from the server directory meanwhile.
+#[derive(Serialize, Debug, Default)]
+pub struct NodeUptime {
+    pub node: String,
+    pub country: String,
+    pub observed_ms: i64,
+    pub online_ms: i64,
+    pub availability: Option<f64>,
+    pub transitions: u32,
+    pub health_checks: i64,
+    pub health_check_failures: i64,
+    pub health_check_success_ratio: Option<f64>
+}
+
+/// Availability of every node in a country, combined.
+#[derive(Serialize, Debug, Default)]
+pub struct CountryUptime {
+    pub country: String,
+    pub nodes: u32,
+    pub observed_ms: i64,
+    pub online_ms: i64,
+    pub availability: Option<f64>,
+    pub health_checks: i64,
+    pub health_check_failures: i64,
+    pub health_check_success_ratio: Option<f64>
+}
+
+#[derive(Serialize, Debug)]
+pub struct UptimeReport {
+    pub from: i64,
+    pub to: i64,
+    pub nodes: Vec<NodeUptime>,
+    pub countries: Vec<CountryUptime>
+}
+
+/// Computes node and country availability over the window in `query` from the recorded state history
+/// and health check rollups. Health checks made since the last rollup are not yet included.
+pub async fn report(pool: &Pool<Any>, query: &UptimeQuery) -> Result<UptimeReport, sqlx::Error> {
+    let to = query.to.unwrap_or_else(|| Utc::now().timestamp_millis());
+    let from = query.from.unwrap_or(to - DEFAULT_REPORT_WINDOW).min(to);
+
+    // The transitions within the window, and the last one of each node before it to know which state the node entered it in.
+    let transitions = sqlx::query_as::<_, Transition>(&placeholders(pool, "\
+        select node_id, country, state, at, id from NodeStateHistory \
+            where (? is null or node_id = ?) and (? is null or country = ?) and at >= ? and at <= ? \
+        union all \
+        select h.node_id, h.country, h.state, h.at, h.id from NodeStateHistory h \
+            inner join (select node_id, max(at) as last_at from NodeStateHistory where (? is null or node_id = ?) and (? is null or country = ?) and at < ? group by node_id) l \
+            on h.node_id = l.node_id and h.at = l.last_at \
+        order by node_id, at, id"))
+        .bind(&query.node)
+        .bind(&query.node)
+        .bind(&query.country)
+        .bind(&query.country)
+        .bind(from)
+        .bind(to)
+        .bind(&query.node)
+        .bind(&query.node)
+        .bind(&query.country)
+        .bind(&query.country)
+        .bind(from)
+        .fetch_all(pool)
+        .await?;
+
+    let rollups = sqlx::query_as::<_, Rollup>(&placeholders(pool, "select node_id, successes, failures from NodeHealthRollup where (? is null or node_id = ?) and (? is null or country = ?) and window_end > ? and window_start < ?"))
+        .bind(&query.node)
+        .bind(&query.node)
+        .bind(&query.country)
+        .bind(&query.country)
+        .bind(from)
+        .bind(to)
+        .fetch_all(pool)
+        .await?;
+
+    let mut nodes: BTreeMap<String, NodeUptime> = BTreeMap::new();
+
+    for (index, transition) in transitions.iter().enumerate() {
+        let uptime = nodes.entry(transition.node_id.clone()).or_insert_with(|| NodeUptime {
+            node: transition.node_id.clone(),
+            country: transition.country.clone(),
+            ..NodeUptime::default()
+        });
+
+        if transition.at >= from {
+            uptime.transitions += 1;
+        }
+
+        // The state holds until the node's next transition, or the end of the window.
+        let until = match transitions.get(index + 1) {
+            Some(next) if next.node_id == transition.node_id => next.at,
+            _ => to
+        };
+
+        let start = transition.at.max(from);
+        let end = until.min(to);
+
+        if end > start && transition.state != REMOVED {
+            uptime.observed_ms += end - start;
+
+            if transition.state == NodeState::Online.name() {
+                uptime.online_ms += end - start;
+            }
+        }
+    }
+
+    for rollup in rollups {
+        if let Some(uptime) = nodes.get_mut(&rollup.node_id) {
+            uptime.health_checks += rollup.successes + rollup.failures;
+            uptime.health_check_failures += rollup.failures;
+        }
+    }
+
+    // Nodes which had already left the mesh before the window opened are of no interest.
+    let nodes = nodes.into_values()
+        .filter(|uptime| uptime.observed_ms > 0 || uptime.transitions > 0 || uptime.health_checks > 0)
+        .map(|mut uptime| {
+            uptime.availability = ratio(uptime.online_ms, uptime.observed_ms);
+            uptime.health_check_success_ratio = ratio(uptime.health_checks - uptime.health_check_failures, uptime.health_checks);
+            uptime
+        })
+        .collect::<Vec<NodeUptime>>();
+
+    let mut countries: BTreeMap<String, CountryUptime> = BTreeMap::new();
+
+    for uptime in nodes.iter() {
+        let country = countries.entry(uptime.country.clone()).or_insert_with(|| CountryUptime {
+            country: uptime.country.clone(),
+            ..CountryUptime::default()
+        });
+
+        country.nodes += 1;
+        country.observed_ms += uptime.observed_ms;
+        country.online_ms += uptime.online_ms;
+        country.health_checks += uptime.health_checks;
+        country.health_check_failures += uptime.health_check_failures;
+    }
+
+    let countries = countries.into_values()
+        .map(|mut country| {
+            country.availability = ratio(country.online_ms, country.observed_ms);
+            country.health_check_success_ratio = ratio(country.health_checks - country.health_check_failures, country.health_checks);
+            country
+        })
+        .collect::<Vec<CountryUptime>>();
+
+    Ok(UptimeReport { from, to, nodes, countries })
+}
+
+fn ratio(part: i64, whole: i64) -> Option<f64> {
+    if whole > 0 {
+        Some(part as f64 / whole as f64)
+    }else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::any::AnyPoolOptions;
+
+    use super::*;
+    use crate::migrate;
+
+    async fn pool() -> Pool<Any> {
+        let pool = AnyPoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+
+        migrate::run(&pool).await.unwrap();
+        pool
+    }
+
+    async fn transition(pool: &Pool<Any>, node: &str, state: &str, at: i64) {
+        sqlx::query("insert into NodeStateHistory (node_id, ip, country, state, cause, at) values (?, ?, ?, ?, ?, ?)")
+            .bind(node)
+            .bind("10.0.0.1")
+            .bind("Netherlands")
+            .bind(state)
+            .bind("test")
+            .bind(at)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    fn window(from: i64, to: i64) -> UptimeQuery {
+        UptimeQuery { node: None, country: None, from: Some(from), to: Some(to) }
+    }
+
+    fn uptime<'a>(report: &'a UptimeReport, node: &str) -> Option<&'a NodeUptime> {
+        report.nodes.iter().find(|uptime| uptime.node == node)
+    }
+
+    #[tokio::test]
+    async fn states_are_clipped_to_the_window() {
+        let pool = pool().await;
+
+        transition(&pool, "node", "registering", 0).await;
+        transition(&pool, "node", "online", 100).await;
+        transition(&pool, "node", "draining", 1_500).await;
+        transition(&pool, "node", "offline", 1_600).await;
+        transition(&pool, "node", "online", 3_000).await;
+
+        let report = report(&pool, &window(1_000, 2_000)).await.unwrap();
+        let node = uptime(&report, "node").unwrap();
+
+        // Online from before the window until 1500, then draining and offline until its end.
+        assert_eq!(node.observed_ms, 1_000);
+        assert_eq!(node.online_ms, 500);
+        assert_eq!(node.transitions, 2);
+        assert_eq!(node.availability, Some(0.5));
+    }
+
+    #[tokio::test]
+    async fn a_node_without_transitions_in_the_window_keeps_its_last_state() {
+        let pool = pool().await;
+
+        transition(&pool, "steady", "registering", 0).await;
+        transition(&pool, "steady", "online", 100).await;
+        transition(&pool, "gone", "online", 0).await;
+        transition(&pool, "gone", REMOVED, 500).await;
+
+        let report = report(&pool, &window(1_000, 2_000)).await.unwrap();
+        let steady = uptime(&report, "steady").unwrap();
+
+        assert_eq!(steady.observed_ms, 1_000);
+        assert_eq!(steady.online_ms, 1_000);
+        assert_eq!(steady.transitions, 0);
+        assert_eq!(steady.availability, Some(1.0));
+
+        // Removed before the window opened, so not reported at all.
+        assert!(uptime(&report, "gone").is_none());
+    }
+
+    #[tokio::test]
+    async fn degraded_time_is_not_online() {
+        let pool = pool().await;
+
+        transition(&pool, "node", "online", 0).await;
+        transition(&pool, "node", "degraded", 1_250).await;
+        transition(&pool, "node", "online", 1_750).await;
+
+        let report = report(&pool, &window(1_000, 2_000)).await.unwrap();
+        let node = uptime(&report, "node").unwrap();
+
+        assert_eq!(node.observed_ms, 1_000);
+        assert_eq!(node.online_ms, 500);
+        assert_eq!(report.countries[0].availability, Some(0.5));
+    }
+}
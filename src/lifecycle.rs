@@ -0,0 +1,231 @@
+use tracing::{info, warn};
+
+use crate::errors::IllegalTransition;
+use crate::events::{publish, EventBus, NodeEvent};
+use crate::models::{Node, NodeState};
+
+/// Number of times a node may be revived after being dismissed before re-registering quarantines it instead.
+pub const MAX_REVIVALS: u64 = 5;
+
+/// The lifecycle of a node:
+///
+/// ```text
+/// Registering -> Online <-> Degraded
+///      |           |           |
+///      v           +-> Draining <-+
+///    Failed              |
+///      |                 v
+///      +-> Registering <- Offline -> Quarantined
+/// ```
+///
+/// `Failed` nodes are purged unless an admin re-drives their instantiation, `Quarantined` nodes are only ever purged.
+/// A node whose Purge has to be retried is quarantined from either `Offline` or `Failed`, as it is left without some of its resources.
+pub fn allowed(from: &NodeState, to: &NodeState) -> bool {
+    matches!((from, to),
+        (NodeState::Registering, NodeState::Online) |
+        (NodeState::Registering, NodeState::Failed) |
+        (NodeState::Failed, NodeState::Registering) |
+        (NodeState::Online, NodeState::Degraded) |
+        (NodeState::Online, NodeState::Draining) |
+        (NodeState::Degraded, NodeState::Online) |
+        (NodeState::Degraded, NodeState::Draining) |
+        (NodeState::Draining, NodeState::Offline) |
+        (NodeState::Offline, NodeState::Registering) |
+        (NodeState::Offline, NodeState::Quarantined) |
+        (NodeState::Failed, NodeState::Quarantined)
+    )
+}
+
+/// Whether a node in `state` is withdrawn for good and may have its DNS records and certificate removed.
+pub fn purgeable(state: &NodeState) -> bool {
+    matches!(state, NodeState::Offline | NodeState::Failed | NodeState::Quarantined)
+}
+
+/// Moves `node` into the state `to` because of `cause`, publishing the change on the event bus.
+///
+/// Every state change goes through here. Asking for the state the node is already in is not an error,
+/// nothing happens and `Ok(false)` is returned; asking for a move the lifecycle does not allow leaves
+/// the node untouched.
+pub fn transition(
+    node: &mut Node,
+    to: NodeState,
+    cause: &str,
+    bus: &EventBus
+) -> Result<bool, IllegalTransition> {
+    if node.state == to {
+        return Ok(false)
+    }
+
+    if !allowed(&node.state, &to) {
+        let err = IllegalTransition {
+            node: node.information.id.clone(),
+            from: node.state.clone(),
+            to
+        };
+
+        warn!(cause, error = %err, "Rejected illegal state transition");
+        return Err(err)
+    }
+
+    let previous = std::mem::replace(&mut node.state, to);
+    publish(bus, NodeEvent::state_changed(node, Some(&previous), cause));
+
+    Ok(true)
+}
+
+/// Brings a dismissed or failed node back into the mesh, moving it to `Registering` for the caller to queue an Instantiate.
+/// The node is given a new generation, which cancels its Purge and any tasks still queued for it.
+///
+/// A node which has already been revived `MAX_REVIVALS` times is quarantined instead and `Ok(false)` returned.
+pub fn revive(
+    node: &mut Node,
+    cause: &str,
+    bus: &EventBus
+) -> Result<bool, IllegalTransition> {
+    if node.state == NodeState::Offline && node.generation >= MAX_REVIVALS {
+        warn!(node = %node.information.id, generation = node.generation, "Dismissed node keeps coming back, quarantining");
+
+        transition(node, NodeState::Quarantined, "revived too often", bus)?;
+        return Ok(false)
+    }
+
+    transition(node, NodeState::Registering, cause, bus)?;
+
+    info!(node = %node.information.id, generation = node.generation, "Revived dismissed node, superseding its generation");
+
+    node.generation += 1;
+    node.checks = Default::default();
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::event_bus;
+    use crate::models::{IpResponse, RegistryReturn};
+
+    fn node(state: NodeState) -> Node {
+        Node {
+            information: RegistryReturn {
+                key: String::new(),
+                cert: String::new(),
+                ip: "10.0.0.1".to_string(),
+                record_id: String::new(),
+                record_dns_id: String::new(),
+                cert_id: String::new(),
+                res: IpResponse {
+                    country: "Netherlands".to_string(),
+                    countryCode: "NL".to_string(),
+                    region: "NH".to_string(),
+                    city: "Amsterdam".to_string(),
+                    lat: 52.37,
+                    lon: 4.89,
+                    timezone: "Europe/Amsterdam".to_string()
+                },
+                id: "node".to_string(),
+                secondary_ip: None,
+                secondary_record_id: None,
+                secondary_record_dns_id: None
+            },
+            state,
+            generation: 0,
+            checks: Default::default(),
+            purged: vec![]
+        }
+    }
+
+    #[test]
+    fn moves_off_the_diagram_are_refused() {
+        let moves = [
+            // A node only leaves the mesh by being drained first.
+            (NodeState::Offline, NodeState::Draining),
+            (NodeState::Online, NodeState::Offline),
+            (NodeState::Degraded, NodeState::Offline),
+            // A node which never became healthy is not published without instantiating again.
+            (NodeState::Failed, NodeState::Online),
+            (NodeState::Offline, NodeState::Online),
+            (NodeState::Draining, NodeState::Online),
+            (NodeState::Registering, NodeState::Draining),
+            // Quarantine is final.
+            (NodeState::Quarantined, NodeState::Registering),
+            (NodeState::Quarantined, NodeState::Offline)
+        ];
+
+        for (from, to) in moves.iter() {
+            assert!(!allowed(from, to), "{} -> {}", from.name(), to.name());
+        }
+    }
+
+    #[test]
+    fn moves_back_into_the_mesh_are_allowed() {
+        // Revived after a dismissal, or an exhausted instantiation re-driven.
+        assert!(allowed(&NodeState::Offline, &NodeState::Registering));
+        assert!(allowed(&NodeState::Failed, &NodeState::Registering));
+
+        // Quarantined when revived too often, or when a purge has to be retried.
+        assert!(allowed(&NodeState::Offline, &NodeState::Quarantined));
+        assert!(allowed(&NodeState::Failed, &NodeState::Quarantined));
+    }
+
+    #[test]
+    fn transition_moves_the_node_and_publishes() {
+        let bus = event_bus();
+        let mut events = bus.subscribe();
+        let mut node = node(NodeState::Registering);
+
+        assert!(transition(&mut node, NodeState::Online, "published", &bus).unwrap());
+        assert_eq!(node.state, NodeState::Online);
+        assert!(matches!(events.try_recv(), Ok(NodeEvent::StateChanged { .. })));
+
+        // Already online, nothing happens.
+        assert!(!transition(&mut node, NodeState::Online, "published", &bus).unwrap());
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn illegal_transitions_leave_the_node_untouched() {
+        let mut node = node(NodeState::Failed);
+
+        let err = transition(&mut node, NodeState::Online, "published", &event_bus()).unwrap_err();
+
+        assert_eq!(err.from, NodeState::Failed);
+        assert_eq!(err.to, NodeState::Online);
+        assert_eq!(node.state, NodeState::Failed);
+    }
+
+    #[test]
+    fn revive_starts_a_new_generation() {
+        let mut node = node(NodeState::Offline);
+        node.checks.failed();
+
+        assert!(revive(&mut node, "re-registered", &event_bus()).unwrap());
+
+        assert_eq!(node.state, NodeState::Registering);
+        assert_eq!(node.generation, 1);
+        assert_eq!(node.checks.failures, 0);
+    }
+
+    #[test]
+    fn revive_quarantines_after_max_revivals() {
+        let bus = event_bus();
+        let mut node = node(NodeState::Offline);
+
+        for _ in 0..MAX_REVIVALS {
+            assert!(revive(&mut node, "re-registered", &bus).unwrap());
+            node.state = NodeState::Offline;
+        }
+
+        assert!(!revive(&mut node, "re-registered", &bus).unwrap());
+        assert_eq!(node.state, NodeState::Quarantined);
+        assert_eq!(node.generation, MAX_REVIVALS);
+    }
+
+    #[test]
+    fn only_live_nodes_are_revived() {
+        let mut node = node(NodeState::Online);
+
+        assert!(revive(&mut node, "re-registered", &event_bus()).is_err());
+        assert_eq!(node.generation, 0);
+    }
+}
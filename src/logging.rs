@@ -0,0 +1,38 @@
+use std::env;
+
+use tracing_subscriber::EnvFilter;
+
+/// Installs the global tracing subscriber.
+///
+/// - `LOG_LEVEL` sets the filter, either a level (`info`) or a full directive (`reseda_mesh=debug,sqlx=warn`). Defaults to `info`.
+/// - `LOG_FORMAT=json` emits one JSON object per line, including the fields of the enclosing spans.
+///
+/// Secrets (the Cloudflare and authentication keys, node private keys) are never logged, `Configuration`
+/// and `RegistryReturn` redact them from their `Debug` output so they cannot leak through a span field either.
+pub fn initialize() {
+    // The environment file is loaded properly by `with_environment`, but the log settings may live in it too.
+    let _ = dotenv::dotenv();
+
+    let filter = match env::var("LOG_LEVEL") {
+        Ok(directive) => EnvFilter::new(directive),
+        Err(_) => EnvFilter::new("info")
+    };
+
+    let json = match env::var("LOG_FORMAT") {
+        Ok(format) => format.eq_ignore_ascii_case("json"),
+        Err(_) => false
+    };
+
+    if json {
+        tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .init();
+    }else {
+        tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .init();
+    }
+}
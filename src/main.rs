@@ -1,17 +1,47 @@
-use models::{NodeStatusResponse, NodeState};
-use routes::json_body;
+use models::{Node, NodeStatusResponse, NodeState};
+use routes::{node_request, registration_request};
 use state::MeshState;
 use tokio::sync::{Mutex, MutexGuard};
 use warp::{self, Filter};
-use std::{sync::{Arc}, convert::Infallible, time::Duration};
-use crate::models::{TaskType, Task};
+use std::{sync::{Arc}, convert::Infallible, time::{Duration, Instant}};
+use crate::admin::Scope;
+use crate::audit::{record, AuditEntry, AuditQuery, MESH_ACTOR};
+use crate::cloudflare::{delete_dns_record, revoke_certificate};
+use crate::dead_letter::bury;
+use crate::directory::{sample_load, Directory, ServerRecord};
+use crate::events::{publish, NodeEvent};
+use crate::history::{count_health_check, UptimeQuery};
+use crate::lifecycle::{purgeable, revive, transition};
+use crate::metrics::{forget_node, observe_health_check, TASKS_ABANDONED, TASKS_EXECUTED, TASK_DURATION, TASK_QUEUE_DEPTH, TASK_RETRIES};
+use crate::models::{TaskType, Task, Tries};
+use crate::rate_limit::{limit_globally, limit_registrations};
 use futures_timer::Delay;
 use chrono::Utc;
-
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+mod admin;
+mod audit;
+mod cloudflare;
+mod dead_letter;
+mod dialect;
+mod directory;
+mod errors;
+mod events;
 mod handlers;
+mod history;
+mod lifecycle;
+mod logging;
+mod metrics;
+mod migrate;
 mod models;
+mod mtls;
+mod rate_limit;
+mod retry;
 mod routes;
+mod saga;
+mod signing;
 mod state;
+mod webhooks;
 
 pub type UnwrappedMesh = Mutex<MeshState>;
 pub type GuardedMesh<'a> = MutexGuard<'a, MeshState>;
@@ -19,6 +49,35 @@ pub type Mesh = Arc<UnwrappedMesh>;
 
 #[tokio::main]
 async fn main() {
+    logging::initialize();
+
+    // `reseda-mesh migrate` applies the database migrations and exits, without starting the mesh.
+    if std::env::args().nth(1).as_deref() == Some("migrate") {
+        let pool = state::connect(&state::with_environment()).await;
+
+        match migrate::run(&pool).await {
+            Ok(_) => info!("Database migrations applied"),
+            Err(err) => {
+                error!(error = %err, "Unable to apply database migrations");
+                std::process::exit(1);
+            }
+        }
+
+        return;
+    }
+
+    // `reseda-mesh token create|revoke ...` manages the admin API tokens, see `admin::command`.
+    if std::env::args().nth(1).as_deref() == Some("token") {
+        let pool = state::connect(&state::with_environment()).await;
+        let arguments = std::env::args().skip(2).collect::<Vec<String>>();
+
+        if !admin::command(&pool, &arguments).await {
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
     let config: Mesh = Arc::new(
         Mutex::new(
             MeshState::initialize().await
@@ -26,414 +85,610 @@ async fn main() {
         )
     );
 
+    let (registrations, verifier, check_key, pool) = {
+        let config_lock = config.lock().await;
+        (config_lock.registrations.clone(), config_lock.signing.clone(), config_lock.keys.check_key.clone(), config_lock.pool.clone())
+    };
+
     let register_route =  warp::path!("register" / String)
         .and(warp::post())
-        .and(json_body())
+        .and(limit_registrations(registrations.clone()))
+        .and(registration_request(verifier.clone(), check_key))
+        .and(limit_globally(registrations))
+        .and(with_config(config.clone()))
+        .and_then(handlers::register_server)
+        .recover(rate_limit::recover)
+        .recover(signing::recover);
+
+    let registration_status_route = warp::path!("register" / "jobs" / String)
+        .and(warp::get())
+        .and(node_request(verifier.clone()))
+        .and(with_config(config.clone()))
+        .and_then(handlers::registration_status)
+        .recover(signing::recover);
+    
+    let metrics_route = warp::path!("metrics")
+        .and(warp::get())
         .and(with_config(config.clone()))
-        .and_then(handlers::register_server);
+        .and_then(handlers::metrics);
+    
+    let events_route = warp::path!("events")
+        .and(warp::get())
+        .and(with_config(config.clone()))
+        .and_then(handlers::events);
+    
+    let webhook_deliveries_route = warp::path!("admin" / "webhooks" / "deliveries")
+        .and(warp::get())
+        .and(admin::require(Scope::ReadOnly, pool.clone()))
+        .and(with_config(config.clone()))
+        .and_then(handlers::webhook_deliveries);
+    
+    let audit_route = warp::path!("admin" / "audit")
+        .and(warp::get())
+        .and(warp::query::<AuditQuery>())
+        .and(admin::require(Scope::ReadOnly, pool.clone()))
+        .and(with_config(config.clone()))
+        .and_then(handlers::audit_log);
+    
+    let uptime_route = warp::path!("admin" / "uptime")
+        .and(warp::get())
+        .and(warp::query::<UptimeQuery>())
+        .and(admin::require(Scope::ReadOnly, pool.clone()))
+        .and(with_config(config.clone()))
+        .and_then(handlers::uptime_report);
+    
+    let dead_letters_route = warp::path!("admin" / "tasks" / "dead")
+        .and(warp::get())
+        .and(admin::require(Scope::ReadOnly, pool.clone()))
+        .and(with_config(config.clone()))
+        .and_then(handlers::dead_letters);
+    
+    let redrive_route = warp::path!("admin" / "tasks" / "dead" / String / "redrive")
+        .and(warp::post())
+        .and(admin::require(Scope::NodeOperator, pool.clone()))
+        .and(with_config(config.clone()))
+        .and_then(handlers::redrive_dead_letter);
     
     let echo_route =  warp::path::end()
         .and(warp::get())
         .and_then(handlers::echo);
 
-    let routes = register_route.or(echo_route).with(warp::cors().allow_any_origin());
+    // Admin routes answer a missing, revoked or insufficiently scoped token themselves, see `admin::recover`.
+    let admin_routes = webhook_deliveries_route.or(audit_route).or(uptime_route).or(dead_letters_route).or(redrive_route)
+        .recover(admin::recover);
+
+    let routes = register_route.or(registration_status_route).or(metrics_route).or(events_route).or(admin_routes).or(echo_route).with(warp::cors().allow_any_origin());
 
     tokio::spawn(async move {
         loop {
-            // println!("[task-runner]: Attempting to start task");
             let config_clone = config.clone();
 
             match tokio::spawn(async move {
+                // The health endpoint is requested before the mesh and task queue are locked for the task, so a node slow to
+                // answer holds up neither the routes nor the other tasks. Only this loop takes tasks off the queue, so the task
+                // at the front is still the one the probe was sent for once the locks are taken again.
+                let mut probe = match probe_target(&config_clone).await {
+                    Some((client, node)) => Some(probe_health(&client, &node).await),
+                    None => None
+                };
+
                 let config_lock = config_clone.lock().await;
                 let mut task_queue_lock = config_lock.task_queue.lock().await;
 
+                TASK_QUEUE_DEPTH.set(task_queue_lock.len() as i64);
+
                 // Execution can proceed, do so...
                 if let Some(current_task) = task_queue_lock.pop_front() {
                     if Utc::now().timestamp_millis() as u128 >= current_task.exec_at {
-                        match current_task.task_type {
-                            // We want to run a routing check to verify if the server is online/offline. If normal, queue a new check task 
-                            models::TaskType::CheckStatus(tries) => {
-                                if tries >= 5 {
-                                    println!("[task]: CheckStatus->Failed: DeniedRetry, Dismissing...");
+                        // Tasks queued for an earlier incarnation of a node (i.e. before it re-registered) are dropped.
+                        let superseded = match config_lock.instance_stack.lock().await.get(&current_task.action_object) {
+                            Some(node) => node.generation != current_task.generation,
+                            None => false
+                        };
+
+                        if superseded {
+                            debug!(task = current_task.task_type.name(), ip = %current_task.action_object, generation = current_task.generation, "Task superseded by re-registration, dropping");
+                            return;
+                        }
+
+                        TASKS_EXECUTED.with_label_values(&[current_task.task_type.name()]).inc();
+                        let _timer = TASK_DURATION.with_label_values(&[current_task.task_type.name()]).start_timer();
+
+                        let node_id = match config_lock.instance_stack.lock().await.get(&current_task.action_object) {
+                            Some(node) => node.information.id.clone(),
+                            None => String::new()
+                        };
+
+                        let span = info_span!("task", task = current_task.task_type.name(), ip = %current_task.action_object, node = %node_id);
+                        let policy = config_lock.retries.for_task(&current_task.task_type);
+
+                        async {
+                            match current_task.task_type {
+                                // We want to run a routing check to verify if the server is online/offline. If normal, queue a new check task 
+                                // A node is only degraded (hidden from the directory) after `degrade_after` consecutive failures, dismissed after
+                                // `dismiss_after`, and restored once it has passed `recover_after` checks in a row, so a short blip does not unpublish it.
+                                models::TaskType::CheckStatus => {
+                                    let node = {
+                                        let stack_lock = config_lock.instance_stack.lock().await;
+
+                                        match stack_lock.get(&current_task.action_object) {
+                                            // Only published nodes are checked. Once a node is draining, whether its checks ran out or it was
+                                            // taken out of the mesh some other way, its Dismiss is queued and the loop ends here.
+                                            Some(val) if matches!(val.state, NodeState::Online | NodeState::Degraded) => val,
+                                            // Gone from the stack, i.e. purged, there is nothing left to check or dismiss.
+                                            _ => {
+                                                debug!("CheckStatus->Node no longer published, ending checks");
+                                                return;
+                                            },
+                                        }.clone()
+                                    };
+
+                                    let response = match take_probe(&mut probe, &node) {
+                                        Some(probe) => check_health(&config_lock, &node, probe).await,
+                                        None => {
+                                            // The node turned up after the probe went out, it is checked on the next pass.
+                                            task_queue_lock.push_back(current_task.clone());
+                                            return;
+                                        }
+                                    };
+                                    let settings = &config_lock.health_checks;
+
+                                    let (id, directory_status, response_failures) = {
+                                        let mut stack_lock = config_lock.instance_stack.lock().await;
+
+                                        let val = match stack_lock.get_mut(&current_task.action_object) {
+                                            Some(val) => val,
+                                            None => return
+                                        };
+
+                                        let directory_status = match response {
+                                            Ok(_) => {
+                                                val.checks.passed();
 
-                                    // If we have been unable to verify the status of the node for more than 5 seconds, we mark it for removal.
-                                    let exec_time = Utc::now().timestamp_millis() as u128 + Duration::new(1, 0).as_millis();
+                                                if val.state == NodeState::Degraded && val.checks.successes >= settings.recover_after {
+                                                    transition(val, NodeState::Online, "health checks passing again", &config_lock.events).ok().map(|_| NodeState::Online)
+                                                }else {
+                                                    None
+                                                }
+                                            },
+                                            Err(_) => {
+                                                val.checks.failed();
+                                                TASK_RETRIES.with_label_values(&["check_status"]).inc();
+                                                publish(&config_lock.events, NodeEvent::health_check_failed(val, val.checks.failures as i16));
+
+                                                // Only `dismiss_after` decides when a node is dismissed, the retry policy merely spaces out the checks.
+                                                if val.checks.failures >= settings.dismiss_after {
+                                                    warn!(failures = val.checks.failures, "CheckStatus->Failed: Dismissing...");
+                                                    TASKS_ABANDONED.with_label_values(&["check_status"]).inc();
+
+                                                    let _ = transition(val, NodeState::Draining, "health checks exhausted", &config_lock.events);
+
+                                                    let exec_time = Utc::now().timestamp_millis() as u128 + Duration::new(1, 0).as_millis();
+
+                                                    task_queue_lock.push_back(Task {
+                                                        task_type: TaskType::Dismiss(0),
+                                                        // Handing over lookup information 
+                                                        action_object: current_task.action_object.to_string(),
+                                                        exec_at: exec_time,
+                                                        generation: current_task.generation,
+                                                        last_error: None
+                                                    });
+
+                                                    return;
+                                                }
+
+                                                if val.state == NodeState::Online && val.checks.failures >= settings.degrade_after {
+                                                    transition(val, NodeState::Degraded, "health checks failing", &config_lock.events).ok().map(|_| NodeState::Degraded)
+                                                }else {
+                                                    None
+                                                }
+                                            }
+                                        };
+
+                                        (val.information.id.clone(), directory_status, val.checks.failures)
+                                    };
+
+                                    // Degraded nodes keep their row, only its status changes so clients stop recommending the node.
+                                    if let Some(state) = directory_status {
+                                        set_directory_status(&config_lock.directory, &id, &state).await;
+                                    }
+
+                                    // A failing node is checked again sooner, backing off towards the regular interval.
+                                    let exec_time = match response_failures {
+                                        0 => Utc::now().timestamp_millis() as u128 + settings.interval.as_millis(),
+                                        failures => policy.next_attempt(failures as Tries)
+                                    };
 
                                     task_queue_lock.push_back(Task {
-                                        task_type: TaskType::Dismiss(0),
+                                        task_type: TaskType::CheckStatus,
                                         // Handing over lookup information 
                                         action_object: current_task.action_object.to_string(),
-                                        exec_at: exec_time
+                                        exec_at: exec_time,
+                                        generation: current_task.generation,
+                                        last_error: None
                                     });
+                                },
+                                // We want to add the node to the network and upgrade its status
+                                models::TaskType::Instantiate(tries) => {
+                                    if policy.exhausted(tries) {
+                                        warn!("Instantiate->Failed: DeniedRetry");
+                                        TASKS_ABANDONED.with_label_values(&["instantiate"]).inc();
+
+                                        // Now we just give up, the retry policy is exhausted. By default that is 6 attempts after the 30s initial delay, backing off from 5s
+                                        // to at most a minute between them, so a few minutes in all. If the node is offline or sending invalid responses (i.e. constantly rebooting after panic! - wrong information - no state persistance)
+                                        // We know that the server has run into issues and we must refuse its request to start.
+                                        // The node is kept as Failed with its DNS records and certificate in place, so re-driving the dead letter can instantiate it again.
+                                        // Otherwise its Purge removes everything created for it in an hour.
+                                        let failed = {
+                                            let mut stack_lock = config_lock.instance_stack.lock().await;
+
+                                            match stack_lock.get_mut(&current_task.action_object) {
+                                                Some(val) => {
+                                                    let _ = transition(val, NodeState::Failed, "instantiation retries exhausted", &config_lock.events);
+                                                    Some(val.clone())
+                                                },
+                                                None => None
+                                            }
+                                        };
 
-                                    return;
-                                }
+                                        if let Some(node) = failed {
+                                            // Its instantiation health checks are the only series the node has, a re-drive starts them again.
+                                            forget_node(&node.information.id, &node.information.res.country);
+                                            publish(&config_lock.events, NodeEvent::instantiation_failed(&node));
+                                            bury(&config_lock.pool, &config_lock.audit, &current_task, Some(node_id.clone())).await;
 
-                                let node = {
-                                    let stack_lock = config_lock.instance_stack.lock().await;
+                                            info!("Instantiate->Failed Instantiating Purge for 3600s from Time::Now");
+                                            let exec_time = Utc::now().timestamp_millis() as u128 + Duration::new(3600, 0).as_millis();
 
-                                    match stack_lock.get(&current_task.action_object) {
-                                        Some(val) => val,
-                                        None => {
-                                            // There is no matching node. We must close it instead.
-                                            let exec_time = Utc::now().timestamp_millis() as u128 + Duration::new(1, 0).as_millis();
-        
                                             task_queue_lock.push_back(Task {
-                                                task_type: TaskType::Dismiss(0),
-                                                // Handing over lookup information 
+                                                task_type: TaskType::Purge(0),
                                                 action_object: current_task.action_object.to_string(),
-                                                exec_at: exec_time
+                                                exec_at: exec_time,
+                                                generation: current_task.generation,
+                                                last_error: None
                                             });
+                                        }
+
+                                        return;
+                                    }
+
+                                    info!("Instantiate->Start");
+
+                                    let node = {
+                                        let stack_lock = config_lock.instance_stack.lock().await;
+
+                                        match stack_lock.get(&current_task.action_object) {
+                                            Some(val) => val,
+                                            None => {
+                                                // There is no matching node. We must close it instead.
+                                                let exec_time = Utc::now().timestamp_millis() as u128 + Duration::new(1, 0).as_millis();
         
-                                            return;
-                                        },
-                                    }.clone()
-                                };
+                                                task_queue_lock.push_back(Task {
+                                                    task_type: TaskType::Dismiss(0),
+                                                    // Handing over lookup information 
+                                                    action_object: current_task.action_object.to_string(),
+                                                    exec_at: exec_time,
+                                                    generation: current_task.generation,
+                                                    last_error: None
+                                                });
+        
+                                                return;
+                                            },
+                                        }.clone()
+                                    };
+
+                                    // This is a partial culmination of a check status and a propagation step. 
+                                    // We need to perform a request to the server, check if it is alive and 'well'
+                                    // If so, we can give the node the status - online and post it to the reseda database.
 
-                                let request_url = format!("https://{}.reseda.app/health", node.information.id);
+                                    // If it does not pass the checks, we can queue another instantiate with an instantiation number increase.
+                                    // Once the retry policy is exhausted, the node is removed.
 
-                                // Perform task
-                                let response = match config_lock.client.get(request_url)
-                                    .header("Content-Type", "application/json")
-                                    .send().await {
+                                    debug!("Instantiate->Pinging Server");
+
+                                    let response = match take_probe(&mut probe, &node) {
+                                        Some(probe) => check_health(&config_lock, &node, probe).await,
+                                        None => {
+                                            // The node turned up after the probe went out, it is checked on the next pass.
+                                            task_queue_lock.push_back(current_task.clone());
+                                            return;
+                                        }
+                                    };
+                                
+                                    // Unwrap the value
+                                    let node_status = match response {
                                         Ok(response) => {
-                                            match response.json::<NodeStatusResponse>().await {
-                                                Ok(res) => Ok(res),
-                                                Err(err) => Err(err),
-                                            }
+                                            debug!("Instantiate->Ping Successful");
+
+                                            response
+                                        },
+                                        Err(err) => {
+                                            info!(tries, "Instantiate->Ping Failed");
+                                            TASK_RETRIES.with_label_values(&["instantiate"]).inc();
+
+                                            // Uh oh, something went wrong. Thats okay, we can just requeue this task with backoff and increment the try counter.
+                                            task_queue_lock.push_back(policy.retry(&current_task, format!("health check failed: {}", err)));
+    
+                                            return;
                                         },
-                                        Err(err) => Err(err),
                                     };
 
-                                let tries_count = match response {
-                                    Ok(_) => 0,
-                                    Err(_) => tries+1
-                                };
-
-                                let mut stack_lock = config_lock.instance_stack.lock().await;
-                                match stack_lock.get_mut(&current_task.action_object) {
-                                    Some(val) => {
-                                        val.state = NodeState::Online;
-                                    },
-                                    None => {},
-                                };
-
-                                // Add another task for the same delay
-                                let exec_time = Utc::now().timestamp_millis() as u128 + Duration::new(1, 0).as_millis();
-
-                                // Readd the task as this will exec every minute
-                                task_queue_lock.push_back(Task {
-                                    task_type: TaskType::CheckStatus(tries_count),
-                                    // Handing over lookup information 
-                                    action_object: current_task.action_object.to_string(),
-                                    exec_at: exec_time
-                                });
-                            },
-                            // We want to add the node to the network and upgrade its status
-                            models::TaskType::Instantiate(tries) => {
-                                if tries >= 6 {
-                                    println!("[task]: Instantiate->Failed: DeniedRetry");
-
-                                    // Now we just give up, we've tried 6 times, after 30s initial delay (far more than necessary)
-                                    // Thus, the total time by the last try is 1 minute. If the node is offline or sending invalid responses (i.e. constantly rebooting after panic! - wrong information - no state persistance)
-                                    // We know that the server has run into issues and we must refuse its request to start.
-                                    return;
-                                }
+                                    debug!("Instantiate->Publishing Server");
 
-                                println!("[task]: Instantiate->Start");
+                                    let result = config_lock.directory.publish(&ServerRecord::new(&node, &node_status)).await;
 
-                                let node = {
-                                    let stack_lock = config_lock.instance_stack.lock().await;
+                                    match result {
+                                        Ok(replaced) => {
+                                            if replaced > 0 {
+                                                warn!(replaced, "Replaced stale server rows published for the same address under another id");
+                                            }
 
-                                    match stack_lock.get(&current_task.action_object) {
-                                        Some(val) => val,
-                                        None => {
-                                            // There is no matching node. We must close it instead.
+                                            info!("Node Published, changing local NodeState to NodeState::Online");
+                                            let mut stack_lock = config_lock.instance_stack.lock().await;
+
+                                            match stack_lock.get_mut(&current_task.action_object) {
+                                                Some(val) => {
+                                                    let _ = transition(val, NodeState::Online, "published to the server directory", &config_lock.events);
+                                                },
+                                                None => {
+                                                    warn!("Was unable to set the state of a node to NodeState::Online in a instantiate task");
+                                                },
+                                            };
+
+                                            debug!("Node Published, creating CheckStatus loop...");
+
+                                            // Once the node has been publicized, we now need to keep monitoring it - we add a new task for 1s time 
+                                            // with the CheckStatus task type, this will then continue for the lifetime of the node.
                                             let exec_time = Utc::now().timestamp_millis() as u128 + Duration::new(1, 0).as_millis();
-        
+
                                             task_queue_lock.push_back(Task {
-                                                task_type: TaskType::Dismiss(0),
+                                                task_type: TaskType::CheckStatus,
                                                 // Handing over lookup information 
                                                 action_object: current_task.action_object.to_string(),
-                                                exec_at: exec_time
+                                                exec_at: exec_time,
+                                                generation: current_task.generation,
+                                                last_error: None
                                             });
-        
-                                            return;
                                         },
-                                    }.clone()
-                                };
+                                        Err(error) => {
+                                            warn!(tries, error = %error, "Unable to publish server due to sqlx error");
+                                            TASK_RETRIES.with_label_values(&["instantiate"]).inc();
 
-                                // This is a partial culmination of a check status and a propagation step. 
-                                // We need to perform a request to the server, check if it is alive and 'well'
-                                // If so, we can give the node the status - online and post it to the reseda database.
-
-                                // If it does not pass the checks, we can queue another instantiate with an instantiation number increase.
-                                // If the tries exceeds 6, the node is removed.
-
-                                println!("[task]: Instantiate->Pinging Server");
-
-                                let request_url = format!("https://{}.reseda.app/health", node.information.id);
-
-                                // Perform task
-                                let response = match config_lock.client.get(request_url)
-                                    .header("Content-Type", "application/json")
-                                    .send().await {
-                                        Ok(response) => {
-                                            match response.json::<NodeStatusResponse>().await {
-                                                Ok(r) => Ok(r),
-                                                Err(err) => Err(err),
-                                            }
+                                            // Uh oh, something went wrong. Thats okay, we can just requeue this task with backoff and increment the try counter.
+                                            task_queue_lock.push_back(policy.retry(&current_task, format!("unable to publish server: {}", error)));
                                         },
-                                        Err(err) => Err(err),
-                                    };
-                                
-                                // Unwrap the value
-                                let _node_status = match response {
-                                    Ok(response) => {
-                                        println!("[task]: Instantiate->Ping Successful");
-
-                                        response
-                                    },
-                                    Err(_) => {
-                                        println!("[task]: Instantiate->Ping Failed");
-
-                                        // Uh oh, something went wrong. Thats okay, we can just requeue this task for 5s time and increment the try counter.
-                                        let exec_time = Utc::now().timestamp_millis() as u128 + Duration::new(5, 0).as_millis();
-
-                                        task_queue_lock.push_back(Task {
-                                            task_type: TaskType::Instantiate(tries+1),
-                                            action_object: current_task.action_object.to_string(),
-                                            exec_at: exec_time
-                                        });
-    
+                                    }
+                                },
+                                // We want to remove the node from the network and set its status accordingly
+                                models::TaskType::Dismiss(tries) => {
+                                    if policy.exhausted(tries) {
+                                        warn!("Dismiss->Failed: DeniedRetry");
+                                        TASKS_ABANDONED.with_label_values(&["dismiss"]).inc();
+
+                                        // The node is left Draining with its row still published until the dead letter is re-driven.
+                                        bury(&config_lock.pool, &config_lock.audit, &current_task, Some(node_id.clone())).await;
                                         return;
-                                    },
-                                };
-
-                                println!("[task]: Instantiate->Publishing Server");
-
-                                // Match the SQLx response for publicizing the server
-                                let result = match config_lock.pool.begin().await {
-                                    Ok(mut transaction) => {
-                                        match sqlx::query!("insert into Server (id, ip, location, country, hostname, flag) values (?, ?, ?, ?, ?, ?)", node.information.id, node.information.ip, node.information.res.timezone, node.information.res.timezone.split("/").collect::<Vec<&str>>()[1], node.information.ip, node.information.res.country.to_lowercase().replace(" ", "-"))
-                                            .execute(&mut transaction)
-                                            .await {
-                                                Ok(result) => {
-                                                    match transaction.commit().await {
-                                                        Ok(_) => {
-                                                            Ok(result)
-                                                        },
-                                                        Err(error) => { 
-                                                            Err(error) 
-                                                        }
-                                                    }
-                                                },
-                                                Err(error) => {
-                                                    Err(error)
-                                                }
-                                            }
-                                    },
-                                    Err(error) => {
-                                        Err(error)
                                     }
-                                };
 
-                                match result {
-                                    Ok(_) => {
-                                        println!("[task]: Node Published, changing local NodeState to NodeState::Online");
-                                        let mut stack_lock = config_lock.instance_stack.lock().await;
+                                    info!("Dismiss->Start");
 
-                                        match stack_lock.get_mut(&current_task.action_object) {
-                                            Some(val) => {
-                                                val.state = NodeState::Online
-                                            },
+                                    let node = {
+                                        let stack_lock = config_lock.instance_stack.lock().await;
+
+                                        match stack_lock.get(&current_task.action_object) {
+                                            Some(val) => val,
                                             None => {
-                                                println!("[task]: Was unable to set the state of a node to NodeState::Online in a instantiate task");
+                                                // There is no matching node. We must close it instead.
+                                                task_queue_lock.push_back(policy.retry(&current_task, "node not found".to_string()));
+        
+                                                return;
                                             },
-                                        };
+                                        }.clone()
+                                    };
 
-                                        println!("[task]: Node Published, creating CheckStatus loop... ");
-
-                                        // Once the node has been publicized, we now need to keep monitoring it - we add a new task for 1s time 
-                                        // with the CheckStatus task type, this will then continue for the lifetime of the node.
-                                        let exec_time = Utc::now().timestamp_millis() as u128 + Duration::new(1, 0).as_millis();
-
-                                        task_queue_lock.push_back(Task {
-                                            task_type: TaskType::CheckStatus(0),
-                                            // Handing over lookup information 
-                                            action_object: current_task.action_object.to_string(),
-                                            exec_at: exec_time
-                                        });
-                                    },
-                                    Err(error) => {
-                                        println!("[task]: Unable to publish server due to sqlx error: {:?}", error);
-
-                                        // Uh oh, something went wrong. Thats okay, we can just requeue this task for 5s time and increment the try counter.
-                                        let exec_time = Utc::now().timestamp_millis() as u128 + Duration::new(5, 0).as_millis();
-
-                                        task_queue_lock.push_back(Task {
-                                            task_type: TaskType::Instantiate(tries+1),
-                                            action_object: current_task.action_object.to_string(),
-                                            exec_at: exec_time
-                                        });
-                                    },
-                                }
-                            },
-                            // We want to remove the node from the network and set its status accordingly
-                            models::TaskType::Dismiss(tries) => {
-                                if tries >= 6 { 
-                                    println!("[task]: CheckStatus->Failed: DeniedRetry");
-                                    return; 
-                                }
+                                    let result = config_lock.directory.unpublish(&node.information.id).await;
 
-                                println!("[task]: Dismiss->Start");
+                                    match result {
+                                        Ok(_) => {
+                                            let mut stack_lock = config_lock.instance_stack.lock().await;
 
-                                let node = {
-                                    let stack_lock = config_lock.instance_stack.lock().await;
+                                            // The node is now removed, we no longer have to monitor it can can safely ignore it.
+                                            // We must set its state to offline as the node is no longer active on the mesh.
+                                            // If we wish to instantiate it - i.e. we receive a new request from the server later
+                                            // as it finishes the initialization after an update -> we can read from this and skip much of the init setup.
+                                            match stack_lock.get_mut(&current_task.action_object) {
+                                                Some(val) => {
+                                                    let _ = transition(val, NodeState::Offline, "dismissed from the server directory", &config_lock.events);
+                                                },
+                                                None => {
+                                                    warn!("Was unable to set the state of a node to offline in a dismissal task");
+                                                },
+                                            };
+
+                                            info!("Dismiss->Complete Instantiating Purge for 3600s from Time::Now");
+
+                                            // We have set the server offline, in the meantime we will count down till its removal. 
+                                            // If it comes back on in the meantime, this task will simply be skipped. Task is set for 1h time.
+                                            let exec_time = Utc::now().timestamp_millis() as u128 + Duration::new(3600, 0).as_millis();
 
-                                    match stack_lock.get(&current_task.action_object) {
-                                        Some(val) => val,
-                                        None => {
-                                            // There is no matching node. We must close it instead.
-                                            let exec_time = Utc::now().timestamp_millis() as u128 + Duration::new(1, 0).as_millis();
-        
                                             task_queue_lock.push_back(Task {
-                                                task_type: TaskType::Dismiss(tries+1),
-                                                // Handing over lookup information 
+                                                task_type: TaskType::Purge(0),
                                                 action_object: current_task.action_object.to_string(),
-                                                exec_at: exec_time
+                                                exec_at: exec_time,
+                                                generation: current_task.generation,
+                                                last_error: None
                                             });
-        
+
+                                            // Until then, keep probing the node so it is published again should it recover by itself.
+                                            let exec_time = config_lock.retries.recover.next_attempt(0);
+
+                                            task_queue_lock.push_back(Task {
+                                                task_type: TaskType::Recover,
+                                                action_object: current_task.action_object.to_string(),
+                                                exec_at: exec_time,
+                                                generation: current_task.generation,
+                                                last_error: None
+                                            });
+                                        },
+                                        Err(error) => {
+                                            warn!(tries, error = %error, "Dismiss->Failure Retrying Dismiss");
+                                            TASK_RETRIES.with_label_values(&["dismiss"]).inc();
+
+                                            // Uh oh, something went wrong. Thats okay, we can just requeue this task with backoff and increment the try counter.
+                                            task_queue_lock.push_back(policy.retry(&current_task, format!("unable to unpublish server: {}", error)));
+                                        },
+                                    }
+                                },
+                                // A dismissed node which passes `recover_after` probes in a row is revived, as if it had re-registered.
+                                models::TaskType::Recover => {
+                                    let node = match config_lock.instance_stack.lock().await.get(&current_task.action_object) {
+                                        // Only dismissed nodes are probed, anything else has been revived, quarantined or purged in the meantime.
+                                        Some(val) if val.state == NodeState::Offline => val.clone(),
+                                        _ => return
+                                    };
+
+                                    let response = match take_probe(&mut probe, &node) {
+                                        Some(probe) => check_health(&config_lock, &node, probe).await,
+                                        None => {
+                                            // The node turned up after the probe went out, it is checked on the next pass.
+                                            task_queue_lock.push_back(current_task.clone());
                                             return;
+                                        }
+                                    };
+                                    let mut stack_lock = config_lock.instance_stack.lock().await;
+
+                                    let val = match stack_lock.get_mut(&current_task.action_object) {
+                                        Some(val) => val,
+                                        None => return
+                                    };
+
+                                    let outcome = match response {
+                                        Ok(_) => {
+                                            val.checks.passed();
+                                            format!("{} consecutive checks passed", val.checks.successes)
                                         },
-                                    }.clone()
-                                };
-
-                                let result = match config_lock.pool.begin().await {
-                                    Ok(mut transaction) => {
-                                        match sqlx::query!("delete from Server where id = ?", node.information.id)
-                                            .execute(&mut transaction)
-                                            .await {
-                                                Ok(result) => {
-                                                    match transaction.commit().await {
-                                                        Ok(_) => {
-                                                            Ok(result)
-                                                        },
-                                                        Err(error) => { 
-                                                            Err(error) 
-                                                        }
-                                                    }
-                                                },
-                                                Err(error) => {
-                                                    Err(error)
-                                                }
-                                            }
-                                    },
-                                    Err(error) => {
-                                        Err(error)
+                                        Err(err) => {
+                                            val.checks.failed();
+                                            format!("health check failed: {}", err)
+                                        }
+                                    };
+
+                                    if val.checks.successes >= config_lock.health_checks.recover_after {
+                                        info!(successes = val.checks.successes, "Recover->Node healthy again, republishing");
+
+                                        if let Ok(true) = revive(val, "recovered after dismissal", &config_lock.events) {
+                                            task_queue_lock.push_back(Task {
+                                                task_type: TaskType::Instantiate(0),
+                                                action_object: current_task.action_object.to_string(),
+                                                exec_at: Utc::now().timestamp_millis() as u128,
+                                                generation: val.generation,
+                                                last_error: None
+                                            });
+                                        }
+
+                                        return;
                                     }
-                                };
 
-                                // Now it is no longer publicly advertised - although before we drop the information we best cleanup the cloudflare configuration...
-                                match result {
-                                    Ok(_) => {
-                                        let mut stack_lock = config_lock.instance_stack.lock().await;
+                                    task_queue_lock.push_back(policy.retry(&current_task, outcome));
+                                },
+                                // We want to remove a server completely from the network and its trace information
+                                models::TaskType::Purge(tries) => {
+                                    if policy.exhausted(tries) {
+                                        warn!("Purge->Failed: DeniedRetry");
+                                        TASKS_ABANDONED.with_label_values(&["purge"]).inc();
 
-                                        // The node is now removed, we no longer have to monitor it can can safely ignore it.
-                                        // We must set its state to offline as the node is no longer active on the mesh.
-                                        // If we wish to instantiate it - i.e. we receive a new request from the server later
-                                        // as it finishes the initialization after an update -> we can read from this and skip much of the init setup.
-                                        match stack_lock.get_mut(&current_task.action_object) {
-                                            Some(val) => {
-                                                val.state = NodeState::Offline
-                                            },
+                                        // The node is left quarantined with whatever could not be removed until the dead letter is re-driven.
+                                        bury(&config_lock.pool, &config_lock.audit, &current_task, Some(node_id.clone())).await;
+                                        return;
+                                    }
+
+                                    debug!("Purge->Start");
+
+                                    // Check if this is not necessary
+                                    let node = {
+                                        let stack_lock = config_lock.instance_stack.lock().await;
+
+                                        match stack_lock.get(&current_task.action_object) {
+                                            Some(val) => val,
                                             None => {
-                                                println!("Was unable to set the state of a node to offline in a dismissal task");
+                                                return;
                                             },
-                                        };
+                                        }.clone()
+                                    };
 
-                                        println!("[task]: Dismiss->Complete Instantiating Purge for 3600s from Time::Now");
-
-                                        // We have set the server offline, in the meantime we will count down till its removal. 
-                                        // If it comes back on in the meantime, this task will simply be skipped. Task is set for 1h time.
-                                        let exec_time = Utc::now().timestamp_millis() as u128 + Duration::new(3600, 0).as_millis();
-
-                                        task_queue_lock.push_back(Task {
-                                            task_type: TaskType::Purge,
-                                            action_object: current_task.action_object.to_string(),
-                                            exec_at: exec_time
-                                        });
-                                    },
-                                    Err(_) => {
-                                        println!("[task]: Dismiss->Failure Retrying Dismiss Time::Now");
-
-                                        // Uh oh, something went wrong. Thats okay, we can just requeue this task for 5s time and increment the try counter.
-                                        let exec_time = Utc::now().timestamp_millis() as u128 + Duration::new(5, 0).as_millis();
-
-                                        task_queue_lock.push_back(Task {
-                                            task_type: TaskType::Dismiss(tries+1),
-                                            action_object: current_task.action_object.to_string(),
-                                            exec_at: exec_time
-                                        });
-                                    },
-                                }
-                            },
-                            // We want to remove a server completely from the network and its trace information
-                            models::TaskType::Purge => {
-                                println!("[task]: Purge->Start");
+                                    info!("Purge->Neccesary");
 
-                                // Check if this is not necessary
-                                let node = {
-                                    let stack_lock = config_lock.instance_stack.lock().await;
+                                    if !purgeable(&node.state) {
+                                        // If the node was brought up in the 1h since this task was queued; we can just skip this task safely.
+                                        return;
+                                    }
 
-                                    match stack_lock.get(&current_task.action_object) {
-                                        Some(val) => val,
-                                        None => {
-                                            return;
-                                        },
-                                    }.clone()
-                                };
+                                    // After a while, we want to completely erase a server from the mesh as it obviously is not coming back online
+                                    // Furthermore, it is cluttering the cloudflare configurations, and repeated usages of this dying server that never revives
+                                    // will leave many upon DNS and SSL records that are 1. not monitored and 2. unregistered by reseda for possibly impersonation 
+                                    // by another server which will inherit the IP from the dead server. This is a liability and so we must clean it up after a set time period.
+
+                                    // First remove the DNS records for the id, then revoke its certificate. Anything removed by an earlier attempt is skipped.
+                                    let record_ids = [&node.information.record_id, &node.information.record_dns_id].into_iter()
+                                        .chain(node.information.secondary_record_id.iter())
+                                        .chain(node.information.secondary_record_dns_id.iter())
+                                        .filter(|record_id| !node.purged.contains(record_id));
+
+                                    let mut purged = vec![];
+                                    let mut failures = vec![];
+
+                                    for record_id in record_ids {
+                                        match delete_dns_record(&config_lock.keys.cloudflare_key, &config_lock.client, record_id).await {
+                                            Ok(_) => {
+                                                record(&config_lock.audit, AuditEntry::for_node(MESH_ACTOR, "resource_deleted", &node.information, format!("dns record {}, node purged", record_id)));
+                                                purged.push(record_id.clone());
+                                            },
+                                            Err(err) => {
+                                                warn!(tries, record = %record_id, error = %err, "Purge->Unable to remove DNS record");
+                                                record(&config_lock.audit, AuditEntry::for_node(MESH_ACTOR, "resource_delete_failed", &node.information, format!("dns record {}, {}", record_id, err)));
+                                                failures.push(format!("dns record {}: {}", record_id, err));
+                                            }
+                                        }
+                                    }
 
-                                println!("[task]: Purge->Neccesary");
+                                    if !node.purged.contains(&node.information.cert_id) {
+                                        match revoke_certificate(&config_lock.keys.cloudflare_key, &config_lock.client, &node.information.cert_id).await {
+                                            Ok(_) => {
+                                                record(&config_lock.audit, AuditEntry::for_node(MESH_ACTOR, "resource_deleted", &node.information, format!("certificate {}, node purged", node.information.cert_id)));
+                                                purged.push(node.information.cert_id.clone());
+                                            },
+                                            Err(err) => {
+                                                warn!(tries, certificate = %node.information.cert_id, error = %err, "Purge->Unable to revoke certificate");
+                                                record(&config_lock.audit, AuditEntry::for_node(MESH_ACTOR, "resource_delete_failed", &node.information, format!("certificate {}, {}", node.information.cert_id, err)));
+                                                failures.push(format!("certificate {}: {}", node.information.cert_id, err));
+                                            }
+                                        }
+                                    }
 
-                                if node.state == NodeState::Online || node.state == NodeState::Registering {
-                                    // If the node was brought up in the 1h since this task was queued; we can just skip this task safely.
-                                    return;
-                                }
+                                    let mut stack_lock = config_lock.instance_stack.lock().await;
 
-                                // After a while, we want to completely erase a server from the mesh as it obviously is not coming back online
-                                // Furthermore, it is cluttering the cloudflare configurations, and repeated usages of this dying server that never revives
-                                // will leave many upon DNS and SSL records that are 1. not monitored and 2. unregistered by reseda for possibly impersonation 
-                                // by another server which will inherit the IP from the dead server. This is a liability and so we must clean it up after a set time period.
-
-                                // First remove the DNS record for the id.
-                                let _remove_record = match config_lock.client.delete(format!("https://api.cloudflare.com/client/v4/zones/ebb52f1687a35641237774c39391ba2a/dns_records/{}", node.information.record_id))
-                                    .header("Authorization", format!("Bearer {}", config_lock.keys.cloudflare_key))
-                                    .send().await {
-                                        Ok(response) => Ok(response),
-                                        Err(err) => Err(err),
-                                    };
+                                    if !failures.is_empty() {
+                                        TASK_RETRIES.with_label_values(&["purge"]).inc();
 
-                                let _remove_dns_record = match config_lock.client.delete(format!("https://api.cloudflare.com/client/v4/zones/ebb52f1687a35641237774c39391ba2a/dns_records/{}", node.information.record_dns_id))
-                                    .header("Authorization", format!("Bearer {}", config_lock.keys.cloudflare_key))
-                                    .send().await {
-                                        Ok(response) => Ok(response),
-                                        Err(err) => Err(err),
-                                    };
+                                        // Part of the node is gone, so it may no longer be revived by re-registering or re-driving its instantiation.
+                                        if let Some(val) = stack_lock.get_mut(&current_task.action_object) {
+                                            val.purged.extend(purged);
+                                            let _ = transition(val, NodeState::Quarantined, "purge incomplete", &config_lock.events);
+                                        }
 
-                                let _remove_cert = match config_lock.client.delete(format!("https://api.cloudflare.com/client/v4/certificates/{}", node.information.cert_id))
-                                    .header("Authorization", format!("Bearer {}", config_lock.keys.cloudflare_key))
-                                    .send().await {
-                                        Ok(response) => Ok(response),
-                                        Err(err) => Err(err),
-                                    };
-                                
-                                println!("[task]: Purge->Removed");
+                                        task_queue_lock.push_back(policy.retry(&current_task, failures.join(", ")));
+                                        return;
+                                    }
 
-                                let mut stack_lock = config_lock.instance_stack.lock().await;
-                                stack_lock.remove(&current_task.action_object);
+                                    info!("Purge->Removed");
+
+                                    forget_node(&node.information.id, &node.information.res.country);
+                                    stack_lock.remove(&current_task.action_object);
+
+                                    publish(&config_lock.events, NodeEvent::purged(&node));
+                                }
                             }
-                        }
+                        }.instrument(span).await
                     }else {
                         // If task cannot be completed, push it to the back of the queue and try process the next one.
                         // This intends to maximize priority tasks by ensuring they are processed first, and that delayed tasks are processed as intended.
@@ -476,11 +731,79 @@ async fn main() {
         }
     });
 
-    warp::serve(routes)
-        .tls()
-        .cert_path("cert.pem")
-        .key_path("key.pem")
-        .run(([0, 0, 0, 0], 443)).await;
+    match mtls::client_ca() {
+        Some(client_ca) => mtls::serve_mutual(routes, client_ca, ([0, 0, 0, 0], 443).into()).await,
+        None => {
+            warp::serve(routes)
+                .tls()
+                .cert_path("cert.pem")
+                .key_path("key.pem")
+                .run(([0, 0, 0, 0], 443)).await;
+        }
+    }
+}
+
+/// The answer of a node's health endpoint, requested without any lock held and recorded by `check_health` once they are.
+struct Probe {
+    id: String,
+    elapsed: f64,
+    response: Result<NodeStatusResponse, reqwest::Error>
+}
+
+/// The node the task at the front of the queue is about to health check, if that task is due and probes one.
+async fn probe_target(config: &Mesh) -> Option<(reqwest::Client, Node)> {
+    let config_lock = config.lock().await;
+    let task_queue_lock = config_lock.task_queue.lock().await;
+
+    let task = task_queue_lock.front()
+        .filter(|task| task.task_type.probes() && Utc::now().timestamp_millis() as u128 >= task.exec_at)?;
+    let node = config_lock.instance_stack.lock().await.get(&task.action_object).cloned()?;
+
+    Some((config_lock.health_client.clone(), node))
+}
+
+/// Requests the health endpoint of `node`, giving up after the client's timeout.
+async fn probe_health(client: &reqwest::Client, node: &Node) -> Probe {
+    let request_url = format!("https://{}.reseda.app/health", node.information.id);
+    let started = Instant::now();
+
+    let response = match client.get(request_url)
+        .header("Content-Type", "application/json")
+        .send().await {
+            Ok(response) => response.json::<NodeStatusResponse>().await,
+            Err(err) => Err(err),
+        };
+
+    Probe { id: node.information.id.clone(), elapsed: started.elapsed().as_secs_f64(), response }
+}
+
+/// The probe sent for `node`, unless it was sent for another node or none was sent at all.
+fn take_probe(probe: &mut Option<Probe>, node: &Node) -> Option<Probe> {
+    match probe.take() {
+        Some(probe) if probe.id == node.information.id => Some(probe),
+        _ => None
+    }
+}
+
+/// Records the outcome of `probe` in the metrics and uptime tallies and samples the node's load.
+async fn check_health(config_lock: &GuardedMesh<'_>, node: &Node, probe: Probe) -> Result<NodeStatusResponse, reqwest::Error> {
+    let response = probe.response;
+
+    observe_health_check(&node.information.id, &node.information.res.country, response.is_ok(), probe.elapsed);
+    count_health_check(&config_lock.health, node, response.is_ok()).await;
+
+    if let Ok(status) = &response {
+        sample_load(&config_lock.load, node, status).await;
+    }
+
+    response
+}
+
+/// Sets the `status` of the node's row in the `Server` table, clients only recommend nodes which are online.
+async fn set_directory_status(directory: &Directory, id: &String, state: &NodeState) {
+    if let Err(err) = directory.update_status(id, state).await {
+        error!(node = %id, status = state.name(), error = %err, "Unable to update server directory status");
+    }
 }
 
 fn with_config(config: Mesh) -> impl Filter<Extract = (Mesh,), Error = Infallible> + Clone {
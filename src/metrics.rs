@@ -0,0 +1,128 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    Encoder, Histogram, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder
+};
+
+use crate::errors::ProviderError;
+
+// Metrics are registered once against the default registry and rendered by the `/metrics` route.
+lazy_static! {
+    pub static ref TASKS_EXECUTED: IntCounterVec = register_int_counter_vec!(
+        "mesh_tasks_executed_total",
+        "Tasks taken off the queue and executed, by task type.",
+        &["task"]
+    ).unwrap();
+
+    pub static ref TASK_RETRIES: IntCounterVec = register_int_counter_vec!(
+        "mesh_task_retries_total",
+        "Tasks requeued after a failed attempt, by task type.",
+        &["task"]
+    ).unwrap();
+
+    pub static ref TASKS_ABANDONED: IntCounterVec = register_int_counter_vec!(
+        "mesh_tasks_abandoned_total",
+        "Tasks given up on after exhausting their tries, by task type.",
+        &["task"]
+    ).unwrap();
+
+    pub static ref TASK_DURATION: HistogramVec = register_histogram_vec!(
+        "mesh_task_duration_seconds",
+        "Time taken to execute a task, by task type.",
+        &["task"]
+    ).unwrap();
+
+    pub static ref TASK_QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "mesh_task_queue_depth",
+        "Number of tasks waiting in the queue."
+    ).unwrap();
+
+    pub static ref REGISTRATIONS: IntCounterVec = register_int_counter_vec!(
+        "mesh_registrations_total",
+        "Registration requests by outcome (rate_limited, forbidden, invalid, conflict, accepted, completed, failed).",
+        &["outcome"]
+    ).unwrap();
+
+    pub static ref REGISTRATION_DURATION: Histogram = register_histogram!(
+        "mesh_registration_duration_seconds",
+        "Time taken to provision a newly registering node.",
+        vec![0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0]
+    ).unwrap();
+
+    pub static ref PROVIDER_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "mesh_provider_requests_total",
+        "Requests made to external providers, by operation and outcome.",
+        &["operation", "outcome"]
+    ).unwrap();
+
+    pub static ref PROVIDER_DURATION: HistogramVec = register_histogram_vec!(
+        "mesh_provider_request_duration_seconds",
+        "Time taken by requests to external providers, by operation.",
+        &["operation"]
+    ).unwrap();
+
+    pub static ref NODES: IntGaugeVec = register_int_gauge_vec!(
+        "mesh_nodes",
+        "Number of nodes known to the mesh, by state.",
+        &["state"]
+    ).unwrap();
+
+    pub static ref NODE_UP: IntGaugeVec = register_int_gauge_vec!(
+        "mesh_node_up",
+        "Whether the last health check of a node succeeded.",
+        &["node", "country"]
+    ).unwrap();
+
+    pub static ref HEALTH_CHECKS: IntCounterVec = register_int_counter_vec!(
+        "mesh_health_checks_total",
+        "Health checks performed against nodes, by outcome (success, failure).",
+        &["node", "country", "outcome"]
+    ).unwrap();
+
+    pub static ref HEALTH_CHECK_DURATION: HistogramVec = register_histogram_vec!(
+        "mesh_health_check_duration_seconds",
+        "Time taken by a node to answer its health check.",
+        &["node", "country"]
+    ).unwrap();
+}
+
+/// Records the outcome of a provider request under `operation`.
+pub fn observe_provider<T>(operation: &str, result: &Result<T, ProviderError>) {
+    let outcome = match result {
+        Ok(_) => "success",
+        Err(ProviderError::Transport(_)) => "transport_error",
+        Err(ProviderError::Malformed(_)) => "malformed",
+        Err(ProviderError::Rejected { .. }) => "rejected"
+    };
+
+    PROVIDER_REQUESTS.with_label_values(&[operation, outcome]).inc();
+}
+
+/// Records the result of a health check against a node.
+pub fn observe_health_check(node: &str, country: &str, success: bool, seconds: f64) {
+    let outcome = if success { "success" } else { "failure" };
+
+    HEALTH_CHECKS.with_label_values(&[node, country, outcome]).inc();
+    HEALTH_CHECK_DURATION.with_label_values(&[node, country]).observe(seconds);
+    NODE_UP.with_label_values(&[node, country]).set(success as i64);
+}
+
+/// Drops the per-node series of a node which has left the mesh.
+pub fn forget_node(node: &str, country: &str) {
+    let _ = NODE_UP.remove_label_values(&[node, country]);
+    let _ = HEALTH_CHECK_DURATION.remove_label_values(&[node, country]);
+
+    for outcome in ["success", "failure"] {
+        let _ = HEALTH_CHECKS.remove_label_values(&[node, country, outcome]);
+    }
+}
+
+/// Renders every registered metric in the Prometheus text exposition format.
+pub fn render() -> Result<String, prometheus::Error> {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+
+    encoder.encode(&prometheus::gather(), &mut buffer)?;
+
+    Ok(String::from_utf8_lossy(&buffer).to_string())
+}
@@ -0,0 +1,34 @@
+use std::env;
+
+use sqlx::{Any, Pool};
+use sqlx::any::AnyKind;
+use sqlx::migrate::{MigrateError, Migrator};
+use tracing::info;
+
+/// The migrations of each supported database, embedded into the binary when it is built.
+static MYSQL: Migrator = sqlx::migrate!("./migrations/mysql");
+static POSTGRES: Migrator = sqlx::migrate!("./migrations/postgres");
+static SQLITE: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+/// Applies every migration which has not yet been applied to the database. Already applied migrations are
+/// checked against their recorded checksum, so an edited migration is reported rather than silently skipped.
+pub async fn run(pool: &Pool<Any>) -> Result<(), MigrateError> {
+    let migrator = match pool.any_kind() {
+        AnyKind::MySql => &MYSQL,
+        AnyKind::Postgres => &POSTGRES,
+        AnyKind::Sqlite => &SQLITE
+    };
+
+    info!(database = ?pool.any_kind(), migrations = migrator.iter().count(), "Applying pending database migrations");
+
+    migrator.run(pool).await
+}
+
+/// Whether to apply migrations as the mesh starts. Set `MIGRATE_ON_START=false` where migrations are
+/// instead run ahead of a deploy with `reseda-mesh migrate`.
+pub fn on_start() -> bool {
+    match env::var("MIGRATE_ON_START") {
+        Ok(val) => !val.eq_ignore_ascii_case("false"),
+        Err(_) => true
+    }
+}
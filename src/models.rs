@@ -1,12 +1,22 @@
-use std::{os::raw::c_float, sync::Arc, collections::{HashMap, VecDeque}};
+use std::{fmt, os::raw::c_float, sync::Arc, collections::{HashMap, VecDeque}, net::IpAddr, time::Duration};
 
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 
+use crate::errors::{ProviderError, RegistrationError};
+
 /// Represents a customer
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Default, Deserialize, Serialize)]
 pub struct Server {
-    pub auth: String
+    /// The mesh authentication key, left out of signed requests.
+    #[serde(default)]
+    pub auth: String,
+    /// When re-registering, requests a freshly issued certificate and key in place of the current pair.
+    #[serde(default)]
+    pub rotate: bool,
+    /// The second address of a dual-stack node, must be of the other family to the address being registered.
+    #[serde(default)]
+    pub secondary_ip: Option<String>
 }
 
 #[derive(Deserialize, Debug, Serialize, Clone)]
@@ -20,30 +30,56 @@ pub struct IpResponse {
     pub timezone: String
 }
 
+/// A single entry of the `errors` or `messages` arrays Cloudflare attaches to every response.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CloudflareMessage {
+    pub code: i64,
+    pub message: String
+}
+
+/// The envelope shared by every Cloudflare v4 API response. On failure `success` is false,
+/// `result` is null and the reason is described by `errors`.
 #[derive(Deserialize, Debug)]
-pub struct CloudflareReturn {
+pub struct CloudflareResponse<T> {
     pub success: bool,
-    pub result: CloudflareResult
+    #[serde(default)]
+    pub errors: Vec<CloudflareMessage>,
+    #[serde(default)]
+    pub messages: Vec<CloudflareMessage>,
+    pub result: Option<T>
 }
 
+impl<T> CloudflareResponse<T> {
+    /// Unwraps the `result` of a successful response, or returns the reasons given by Cloudflare.
+    pub fn into_result(self) -> Result<T, ProviderError> {
+        match (self.success, self.result) {
+            (true, Some(result)) => Ok(result),
+            (_, _) => Err(ProviderError::Rejected {
+                errors: self.errors,
+                messages: self.messages
+            })
+        }
+    }
+}
+
+pub type CloudflareReturn = CloudflareResponse<CloudflareResult>;
+
 #[derive(Deserialize, Debug)]
 pub struct CloudflareResult {
     pub certificate: String,
     pub id: String
 }
 
-#[derive(Deserialize, Debug)]
-
-pub struct CloudflareDNSRecordCreate {
-    pub success: bool,
-    pub result: CloudflareDNSRecordCreateResult
-}
+pub type CloudflareDNSRecordCreate = CloudflareResponse<CloudflareDNSRecordCreateResult>;
 
 #[derive(Deserialize, Debug)]
 pub struct CloudflareDNSRecordCreateResult {
     pub id: String
 }
 
+/// Deletions only echo back the id of the removed object, which is of no further use.
+pub type CloudflareDelete = CloudflareResponse<serde_json::Value>;
+
 #[derive(Deserialize, Debug)]
 pub struct NodeStatusResponse {
     // The nodes current information so we can verify it is ready to be publicized 
@@ -53,17 +89,28 @@ pub struct NodeStatusResponse {
     // This is information the client has which we request back so that we can verify the server which was booted **matches** the one we have in the local storage
     pub ip: String,
     pub cert: String,
-    pub record_id: String
+    pub record_id: String,
+
+    // Optionally reported by the node and published to the server directory, nodes running older software omit them
+    /// Maximum number of clients the node accepts.
+    #[serde(default)]
+    pub capacity: Option<i64>,
+    /// Tunnelling protocols the node supports, e.g. `wireguard`.
+    #[serde(default)]
+    pub protocols: Vec<String>,
+    /// Version of the software the node runs.
+    #[serde(default)]
+    pub version: Option<String>
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Default, Deserialize, Serialize)]
 pub struct Configuration {
     pub check_key: String,
     pub cloudflare_key: String,
     pub database_key: String
 }
 
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Clone)]
 pub struct RegistryReturn {
     pub key: String,
     pub cert: String,
@@ -74,7 +121,69 @@ pub struct RegistryReturn {
     pub cert_id: String,
     
     pub res: IpResponse,
-    pub id: String
+    pub id: String,
+
+    /// Dual-stack nodes are registered under the same identifier for both of their addresses.
+    pub secondary_ip: Option<String>,
+    pub secondary_record_id: Option<String>,
+    pub secondary_record_dns_id: Option<String>
+}
+
+// The keys and private key below must never reach the logs, so their `Debug` output is redacted.
+const REDACTED: &str = "<redacted>";
+
+impl fmt::Debug for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Server")
+            .field("auth", &REDACTED)
+            .field("rotate", &self.rotate)
+            .field("secondary_ip", &self.secondary_ip)
+            .finish()
+    }
+}
+
+impl fmt::Debug for Configuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Configuration")
+            .field("check_key", &REDACTED)
+            .field("cloudflare_key", &REDACTED)
+            .field("database_key", &REDACTED)
+            .finish()
+    }
+}
+
+impl fmt::Debug for RegistryReturn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegistryReturn")
+            .field("id", &self.id)
+            .field("ip", &self.ip)
+            .field("secondary_ip", &self.secondary_ip)
+            .field("key", &REDACTED)
+            .field("cert_id", &self.cert_id)
+            .field("record_id", &self.record_id)
+            .field("record_dns_id", &self.record_dns_id)
+            .field("secondary_record_id", &self.secondary_record_id)
+            .field("secondary_record_dns_id", &self.secondary_record_dns_id)
+            .field("res", &self.res)
+            .finish()
+    }
+}
+
+impl RegistryReturn {
+    fn addresses(&self) -> Vec<IpAddr> {
+        std::iter::once(&self.ip)
+            .chain(self.secondary_ip.iter())
+            .filter_map(|ip| ip.parse::<IpAddr>().ok())
+            .collect()
+    }
+
+    pub fn ipv4(&self) -> Option<String> {
+        self.addresses().into_iter().find(|ip| ip.is_ipv4()).map(|ip| ip.to_string())
+    }
+
+    pub fn ipv6(&self) -> Option<String> {
+        self.addresses().into_iter().find(|ip| ip.is_ipv6()).map(|ip| ip.to_string())
+    }
 }
 
 pub type Stack = Arc<Mutex<HashMap<String, Node>>>;
@@ -84,34 +193,206 @@ pub struct Node {
     /// This row is all the information exclusively accessible known by the server that was initialized. 
     /// Note, we need to ensure this is all valid and correct, justified and all...
     pub information: RegistryReturn,
-    pub state: NodeState
+    pub state: NodeState,
+    /// Incremented whenever the node re-registers after being dismissed, invalidating any tasks queued for the previous generation.
+    pub generation: u64,
+    pub checks: CheckStreak,
+    /// DNS records and certificate already removed by a Purge, so a retried Purge only removes what is left.
+    pub purged: Vec<String>
+}
+
+/// Consecutive outcomes of the node's most recent health checks. One of the two is always zero.
+#[derive(Clone, Debug, Default)]
+pub struct CheckStreak {
+    pub failures: u32,
+    pub successes: u32
 }
 
+impl CheckStreak {
+    pub fn passed(&mut self) {
+        self.failures = 0;
+        self.successes += 1;
+    }
+
+    pub fn failed(&mut self) {
+        self.successes = 0;
+        self.failures += 1;
+    }
+}
+
+/// How nodes are health checked, read from the environment by `health_check_settings`.
+#[derive(Clone, Debug)]
+pub struct HealthCheckSettings {
+    /// Time between health checks of a published node.
+    pub interval: Duration,
+    /// Consecutive failed checks after which an online node is degraded and hidden from the directory.
+    pub degrade_after: u32,
+    /// Consecutive failed checks after which a node is dismissed from the directory.
+    pub dismiss_after: u32,
+    /// Consecutive passed checks after which a degraded or dismissed node is published again.
+    pub recover_after: u32,
+    /// Time between probes of a dismissed node, to notice it has recovered.
+    pub recovery_interval: Duration,
+    /// Time a node has to answer a health check before it counts as failed.
+    pub timeout: Duration
+}
+
+/// Where a node is in its lifecycle. The allowed moves between states are defined in `lifecycle`.
 #[derive(PartialEq, Clone, Debug)]
 pub enum NodeState {
+    /// Provisioned and waiting to be instantiated.
+    Registering,
+    /// Published in the server directory and passing its health checks.
     Online,
+    /// Published, but failing its health checks.
+    Degraded,
+    /// Being withdrawn from the server directory.
+    Draining,
+    /// Withdrawn from the server directory, purged unless it re-registers.
     Offline,
-    Registering
+    /// Never became healthy after registering, purged unless its instantiation is re-driven.
+    Failed,
+    /// Withdrawn too often to be revived on re-registration, or partly purged, left to be purged.
+    Quarantined
+}
+
+impl NodeState {
+    pub const ALL: [NodeState; 7] = [
+        NodeState::Registering, NodeState::Online, NodeState::Degraded, NodeState::Draining,
+        NodeState::Offline, NodeState::Failed, NodeState::Quarantined
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            NodeState::Registering => "registering",
+            NodeState::Online => "online",
+            NodeState::Degraded => "degraded",
+            NodeState::Draining => "draining",
+            NodeState::Offline => "offline",
+            NodeState::Failed => "failed",
+            NodeState::Quarantined => "quarantined"
+        }
+    }
+}
+
+/// Registration jobs, keyed by job id.
+pub type JobStore = Arc<Mutex<HashMap<String, RegistrationJob>>>;
+
+/// A registration accepted by `register_server` whose provisioning runs in the background.
+#[derive(Clone, Debug)]
+pub struct RegistrationJob {
+    pub ip: String,
+    pub status: JobStatus,
+    pub created_at: u128,
+    /// The certificate a credential rotation replaced, revoked once the node has collected the new one.
+    pub superseded: Option<String>
+}
+
+#[derive(Clone, Debug)]
+pub enum JobStatus {
+    Pending,
+    Complete(Box<RegistryReturn>),
+    Failed(RegistrationError),
+    /// The node has fetched its details, the key material is no longer kept on the job.
+    Collected
+}
+
+/// Returned to a node once its registration has been accepted.
+#[derive(Serialize, Debug)]
+pub struct JobAccepted {
+    pub job_id: String,
+    pub status_url: String
+}
+
+/// Returned when polling a job which has not yet finished.
+#[derive(Serialize, Debug)]
+pub struct JobPending {
+    pub job_id: String,
+    pub status: String
 }
 
 /// For queueing tasks.
 pub type TaskQueue = Arc<Mutex<VecDeque<Task>>>;
 
 /// Relative to the server, task to manage or migrate server items, dynamically created as threads with the multi threaded locked storage.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum TaskType {
-    CheckStatus(Tries),
+    CheckStatus,
     Instantiate(Tries),
     Dismiss(Tries),
-    Purge
+    /// Probes a dismissed node until it is healthy again or purged.
+    Recover,
+    Purge(Tries)
+}
+
+impl TaskType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TaskType::CheckStatus => "check_status",
+            TaskType::Instantiate(_) => "instantiate",
+            TaskType::Dismiss(_) => "dismiss",
+            TaskType::Recover => "recover",
+            TaskType::Purge(_) => "purge"
+        }
+    }
+
+    /// Whether the task requests the node's health endpoint, which the task loop does before taking its locks.
+    pub fn probes(&self) -> bool {
+        matches!(self, TaskType::CheckStatus | TaskType::Instantiate(_) | TaskType::Recover)
+    }
+
+    /// The task called `name`, on its first try. The reverse of `name`.
+    pub fn parse(name: &str) -> Option<TaskType> {
+        match name {
+            "check_status" => Some(TaskType::CheckStatus),
+            "instantiate" => Some(TaskType::Instantiate(0)),
+            "dismiss" => Some(TaskType::Dismiss(0)),
+            "recover" => Some(TaskType::Recover),
+            "purge" => Some(TaskType::Purge(0)),
+            _ => None
+        }
+    }
+
+    /// Failed attempts made at the task so far. Health checks count theirs on the node instead.
+    pub fn tries(&self) -> Tries {
+        match self {
+            TaskType::Instantiate(tries) | TaskType::Dismiss(tries) | TaskType::Purge(tries) => *tries,
+            _ => 0
+        }
+    }
+
+    /// The same task with its failed attempts forgotten.
+    pub fn first_try(&self) -> TaskType {
+        match self {
+            TaskType::Instantiate(_) => TaskType::Instantiate(0),
+            TaskType::Dismiss(_) => TaskType::Dismiss(0),
+            TaskType::Purge(_) => TaskType::Purge(0),
+            other => other.clone()
+        }
+    }
+
+    /// The same task with one more failed attempt counted.
+    pub fn retried(&self) -> TaskType {
+        match self {
+            TaskType::CheckStatus => TaskType::CheckStatus,
+            TaskType::Instantiate(tries) => TaskType::Instantiate(tries + 1),
+            TaskType::Dismiss(tries) => TaskType::Dismiss(tries + 1),
+            TaskType::Recover => TaskType::Recover,
+            TaskType::Purge(tries) => TaskType::Purge(tries + 1)
+        }
+    }
 }
 
 pub type Tries = i16;
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Task {
     pub task_type: TaskType,
     pub action_object: String,
-    pub exec_at: u128
+    pub exec_at: u128,
+    /// The `Node::generation` the task was queued for.
+    pub generation: u64,
+    /// Why the previous attempt at the task failed.
+    pub last_error: Option<String>
 }
@@ -0,0 +1,233 @@
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hyper::server::conn::Http;
+use hyper::service::{service_fn, Service};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tracing::{debug, error, info};
+use warp::{Filter, Reply};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::models::Node;
+
+/// The DER certificate a client presented during the TLS handshake, added to the extensions of every request made
+/// over the connection. Only present once the certificate chained up to the configured CA.
+#[derive(Clone, Debug)]
+pub struct PeerCertificate(pub Vec<u8>);
+
+/// The address of the connected client, added to the extensions of every request as `warp::addr::remote` is not
+/// filled in when warp is served by `serve_mutual`. Read through `routes::remote_address`.
+#[derive(Clone, Copy, Debug)]
+pub struct RemoteAddress(pub SocketAddr);
+
+/// The CA bundle node certificates are checked against, from `MTLS_CLIENT_CA`. When unset the mesh does not ask for
+/// client certificates at all and nodes authenticate with the authentication key or a signature alone.
+pub fn client_ca() -> Option<String> {
+    env::var("MTLS_CLIENT_CA").ok().filter(|path| !path.is_empty())
+}
+
+/// The id of the node a certificate was issued to, read from its `<id>.reseda.app` subject alternative name.
+pub fn node_id(certificate: &PeerCertificate) -> Option<String> {
+    let (_, parsed) = X509Certificate::from_der(&certificate.0).ok()?;
+    let names = parsed.subject_alternative_name().ok()??;
+
+    names.value.general_names.iter().find_map(|name| match name {
+        GeneralName::DNSName(dns) => dns.strip_suffix(".reseda.app").map(|id| id.to_string()),
+        _ => None
+    })
+}
+
+/// Whether `certificate` is the one currently issued to `node`. A certificate replaced by a rotation still chains up
+/// to the CA until it expires, so it is compared against the node's current certificate rather than trusted by name alone.
+pub fn issued_to(certificate: &PeerCertificate, node: &Node) -> bool {
+    if node_id(certificate).as_ref() != Some(&node.information.id) {
+        return false
+    }
+
+    rustls_pemfile::certs(&mut node.information.cert.as_bytes())
+        .filter_map(|cert| cert.ok())
+        .any(|cert| cert.as_ref() == certificate.0.as_slice())
+}
+
+fn server_config(client_ca: &String) -> Result<ServerConfig, String> {
+    let mut roots = RootCertStore::empty();
+
+    for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(client_ca).map_err(|err| format!("{}: {}", client_ca, err))?)) {
+        roots.add(cert.map_err(|err| format!("{}: {}", client_ca, err))?).map_err(|err| format!("{}: {}", client_ca, err))?;
+    }
+
+    // Browsers, the dashboard and nodes which do not (yet) present a certificate still connect, the routes decide
+    // whether a certificate is needed.
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+        .allow_unauthenticated()
+        .build()
+        .map_err(|err| err.to_string())?;
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open("cert.pem").map_err(|err| format!("cert.pem: {}", err))?))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("cert.pem: {}", err))?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open("key.pem").map_err(|err| format!("key.pem: {}", err))?))
+        .map_err(|err| format!("key.pem: {}", err))?
+        .ok_or("key.pem: no private key found")?;
+
+    ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)
+        .map_err(|err| err.to_string())
+}
+
+/// Serves `routes` over TLS on `addr` like `warp::serve(..).tls()`, but also asks clients for a certificate signed
+/// by `client_ca`. The certificate presented on a connection is handed to the routes as a `PeerCertificate` extension.
+pub async fn serve_mutual<F>(routes: F, client_ca: String, addr: SocketAddr)
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: Reply
+{
+    let config = match server_config(&client_ca) {
+        Ok(config) => config,
+        Err(err) => panic!("[err]: Unable to configure mutual TLS, {}", err)
+    };
+
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let service = warp::service(routes);
+
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => panic!("[err]: Unable to listen on {}, {}", addr, err)
+    };
+
+    info!(address = %addr, client_ca = %client_ca, "Listening with mutual TLS");
+
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                error!(error = %err, "Unable to accept connection");
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let service = service.clone();
+
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    debug!(remote = %remote, error = %err, "TLS handshake failed");
+                    return;
+                }
+            };
+
+            let certificate = stream.get_ref().1.peer_certificates()
+                .and_then(|chain| chain.first())
+                .map(|cert| PeerCertificate(cert.as_ref().to_vec()));
+
+            let connection = service_fn(move |mut request| {
+                request.extensions_mut().insert(RemoteAddress(remote));
+
+                if let Some(certificate) = &certificate {
+                    request.extensions_mut().insert(certificate.clone());
+                }
+
+                let mut service = service.clone();
+                service.call(request)
+            });
+
+            if let Err(err) = Http::new().serve_connection(stream, connection).await {
+                debug!(remote = %remote, error = %err, "Connection closed with an error");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::generate_simple_self_signed;
+
+    use super::*;
+    use crate::models::{IpResponse, NodeState, RegistryReturn};
+
+    /// A certificate for `names` as PEM, the form it is handed to the node in, and as the DER the node presents.
+    fn certificate(names: &[&str]) -> (String, PeerCertificate) {
+        let pem = generate_simple_self_signed(names.iter().map(|name| name.to_string()).collect::<Vec<String>>())
+            .unwrap()
+            .serialize_pem()
+            .unwrap();
+
+        let der = rustls_pemfile::certs(&mut pem.as_bytes()).next().unwrap().unwrap();
+
+        (pem, PeerCertificate(der.as_ref().to_vec()))
+    }
+
+    fn node(id: &str, cert: String) -> Node {
+        Node {
+            information: RegistryReturn {
+                key: String::new(),
+                cert,
+                ip: "10.0.0.1".to_string(),
+                record_id: String::new(),
+                record_dns_id: String::new(),
+                cert_id: String::new(),
+                res: IpResponse {
+                    country: "Netherlands".to_string(),
+                    countryCode: "NL".to_string(),
+                    region: "NH".to_string(),
+                    city: "Amsterdam".to_string(),
+                    lat: 52.37,
+                    lon: 4.89,
+                    timezone: "Europe/Amsterdam".to_string()
+                },
+                id: id.to_string(),
+                secondary_ip: None,
+                secondary_record_id: None,
+                secondary_record_dns_id: None
+            },
+            state: NodeState::Online,
+            generation: 0,
+            checks: Default::default(),
+            purged: vec![]
+        }
+    }
+
+    #[test]
+    fn reads_the_node_id_from_the_reseda_name() {
+        let (_, presented) = certificate(&["localhost", "netherlands-node.reseda.app"]);
+        assert_eq!(node_id(&presented).as_deref(), Some("netherlands-node"));
+
+        let (_, presented) = certificate(&["example.com"]);
+        assert_eq!(node_id(&presented), None);
+
+        assert_eq!(node_id(&PeerCertificate(b"not a certificate".to_vec())), None);
+    }
+
+    #[test]
+    fn accepts_the_certificate_issued_to_the_node() {
+        let (pem, presented) = certificate(&["netherlands-node.reseda.app"]);
+
+        assert!(issued_to(&presented, &node("netherlands-node", pem)));
+    }
+
+    #[test]
+    fn rejects_a_certificate_the_node_no_longer_holds() {
+        let (current, _) = certificate(&["netherlands-node.reseda.app"]);
+        let (_, superseded) = certificate(&["netherlands-node.reseda.app"]);
+
+        // Named after the node, but not the certificate it holds now, e.g. one replaced by a rotation.
+        assert!(!issued_to(&superseded, &node("netherlands-node", current)));
+    }
+
+    #[test]
+    fn rejects_a_certificate_of_another_node() {
+        let (pem, presented) = certificate(&["germany-node.reseda.app"]);
+
+        assert!(!issued_to(&presented, &node("netherlands-node", pem.clone())));
+        assert!(issued_to(&presented, &node("germany-node", pem)));
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+use warp::http::StatusCode;
+use warp::reply::{json as json_reply, with_header, with_status};
+use warp::{Filter, Rejection, Reply};
+
+use crate::errors::ErrorReply;
+use crate::metrics::REGISTRATIONS;
+use crate::routes::remote_address;
+
+/// How long a caller is told to wait when every in-flight registration slot is taken.
+const IN_FLIGHT_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// Quotas applied to `POST /register/{ip}`, read from the environment by `registration_limits`.
+#[derive(Clone, Debug)]
+pub struct RateLimits {
+    /// Length of the window the quotas are counted over.
+    pub window: Duration,
+    /// Registrations accepted from one source address per window.
+    pub per_source: u32,
+    /// Registrations accepted from all sources together per window.
+    pub global: u32,
+    /// Registrations being provisioned at once, each of which makes several requests to ip-api and Cloudflare.
+    pub in_flight: usize
+}
+
+/// Requests counted in the current window.
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    count: u32
+}
+
+impl Window {
+    fn new(now: Instant) -> Self {
+        Window { started: now, count: 0 }
+    }
+
+    /// Whether another request fits in `quota`, or how long until the window resets once it is used up.
+    fn check(&mut self, now: Instant, length: Duration, quota: u32) -> Result<(), Duration> {
+        if now.duration_since(self.started) >= length {
+            *self = Window::new(now);
+        }
+
+        match self.count >= quota {
+            true => Err(length - now.duration_since(self.started)),
+            false => Ok(())
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Windows {
+    global: Window,
+    sources: HashMap<String, Window>
+}
+
+/// Fixed window rate limiter and in-flight cap for registrations, shared by every request.
+#[derive(Clone, Debug)]
+pub struct RegistrationLimiter {
+    limits: RateLimits,
+    windows: Arc<Mutex<Windows>>,
+    in_flight: Arc<Semaphore>
+}
+
+/// Held for as long as a registration is being provisioned, freeing its in-flight slot when dropped.
+#[derive(Debug)]
+pub struct RegistrationPermit {
+    _slot: OwnedSemaphorePermit
+}
+
+impl RegistrationLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        RegistrationLimiter {
+            windows: Arc::new(Mutex::new(Windows {
+                global: Window::new(Instant::now()),
+                sources: HashMap::new()
+            })),
+            in_flight: Arc::new(Semaphore::new(limits.in_flight)),
+            limits
+        }
+    }
+
+    async fn admit(&self, source: String) -> Result<RegistrationPermit, RateLimited> {
+        // The slot is taken first so a request turned away for being over capacity does not use up quota.
+        let permit = match self.in_flight.clone().try_acquire_owned() {
+            Ok(slot) => RegistrationPermit { _slot: slot },
+            Err(_) => return Err(RateLimited { scope: "in_flight", retry_after: IN_FLIGHT_RETRY_AFTER })
+        };
+
+        let now = Instant::now();
+        let mut windows = self.windows.lock().await;
+
+        // Forget sources whose window has passed, they start over with a fresh one anyway.
+        let length = self.limits.window;
+        windows.sources.retain(|_, window| now.duration_since(window.started) < length);
+
+        let window = windows.sources.entry(source).or_insert_with(|| Window::new(now));
+
+        if let Err(retry_after) = window.check(now, length, self.limits.per_source) {
+            return Err(RateLimited { scope: "source", retry_after })
+        }
+
+        window.count += 1;
+        Ok(permit)
+    }
+
+    /// Counts the registration against the global quota.
+    async fn admit_globally(&self) -> Result<(), RateLimited> {
+        let mut windows = self.windows.lock().await;
+
+        if let Err(retry_after) = windows.global.check(Instant::now(), self.limits.window, self.limits.global) {
+            return Err(RateLimited { scope: "global", retry_after })
+        }
+
+        windows.global.count += 1;
+        Ok(())
+    }
+}
+
+/// A registration turned away by `limit_registrations`, answered with `429` by `recover`.
+#[derive(Debug)]
+pub struct RateLimited {
+    /// Which limit was hit: `source`, `global` or `in_flight`.
+    pub scope: &'static str,
+    pub retry_after: Duration
+}
+
+impl warp::reject::Reject for RateLimited {}
+
+/// Admits a registration from the requesting address, rejecting it with `RateLimited` when the source's quota is
+/// used up or too many registrations are already in flight. The permit must be held until provisioning ends.
+/// The global quota is only counted once the request is authenticated, see `limit_globally`.
+pub fn limit_registrations(
+    limiter: RegistrationLimiter
+) -> impl Filter<Extract = (RegistrationPermit,), Error = Rejection> + Clone {
+    remote_address()
+        .and(warp::any().map(move || limiter.clone()))
+        .and_then(|remote: Option<SocketAddr>, limiter: RegistrationLimiter| async move {
+            let source = match remote {
+                Some(addr) => addr.ip().to_string(),
+                None => "unknown".to_string()
+            };
+
+            match limiter.admit(source.clone()).await {
+                Ok(permit) => Ok(permit),
+                Err(limited) => {
+                    warn!(source = %source, scope = limited.scope, retry_after = limited.retry_after.as_secs(), "Registration rate limited");
+                    REGISTRATIONS.with_label_values(&["rate_limited"]).inc();
+
+                    Err(warp::reject::custom(limited))
+                }
+            }
+        })
+}
+
+/// Counts an authenticated registration against the quota shared by all sources, rejecting it with `RateLimited`
+/// once that is used up. Placed after `routes::registration_request`, so unauthenticated traffic cannot use up the
+/// quota and lock real nodes out.
+pub fn limit_globally(
+    limiter: RegistrationLimiter
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .map(move || limiter.clone())
+        .and_then(|limiter: RegistrationLimiter| async move {
+            match limiter.admit_globally().await {
+                Ok(()) => Ok(()),
+                Err(limited) => {
+                    warn!(scope = limited.scope, retry_after = limited.retry_after.as_secs(), "Registration rate limited");
+                    REGISTRATIONS.with_label_values(&["rate_limited"]).inc();
+
+                    Err(warp::reject::custom(limited))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+impl RateLimited {
+    /// `429 Too Many Requests` with a `Retry-After` in whole seconds.
+    pub fn reply(&self) -> Box<dyn Reply> {
+        // Rounded up, a client retrying after zero seconds would only be turned away again.
+        let retry_after = self.retry_after.as_secs() + if self.retry_after.subsec_nanos() > 0 { 1 } else { 0 };
+
+        let body = ErrorReply::new(
+            "rate_limited",
+            format!("too many registrations ({} limit), retry after {} seconds", self.scope, retry_after)
+        );
+
+        Box::new(with_header(
+            with_status(json_reply(&body), StatusCode::TOO_MANY_REQUESTS),
+            "Retry-After",
+            retry_after.to_string()
+        ))
+    }
+}
+
+/// Answers a `RateLimited` rejection, any other rejection is passed on untouched.
+pub async fn recover(rejection: Rejection) -> Result<Box<dyn Reply>, Rejection> {
+    match rejection.find::<RateLimited>() {
+        Some(limited) => Ok(limited.reply()),
+        None => Err(rejection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::registration_request;
+    use crate::signing::{self, NodeRequest, RequestVerifier};
+
+    const LENGTH: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn window_admits_up_to_the_quota() {
+        let start = Instant::now();
+        let mut window = Window::new(start);
+
+        for _ in 0..3 {
+            assert!(window.check(start, LENGTH, 3).is_ok());
+            window.count += 1;
+        }
+
+        assert_eq!(window.check(start + Duration::from_secs(15), LENGTH, 3), Err(Duration::from_secs(45)));
+    }
+
+    #[test]
+    fn window_rolls_over_once_its_length_has_passed() {
+        let start = Instant::now();
+        let mut window = Window::new(start);
+        window.count = 3;
+
+        assert!(window.check(start + LENGTH - Duration::from_millis(1), LENGTH, 3).is_err());
+        assert!(window.check(start + LENGTH, LENGTH, 3).is_ok());
+
+        assert_eq!(window.count, 0);
+        assert_eq!(window.started, start + LENGTH);
+    }
+
+    #[tokio::test]
+    async fn global_quota_is_only_used_by_authenticated_requests() {
+        let limiter = RegistrationLimiter::new(RateLimits { window: LENGTH, per_source: 10, global: 1, in_flight: 4 });
+        let verifier = RequestVerifier::new("signing-key".to_string(), false);
+
+        let route = warp::post()
+            .and(limit_registrations(limiter.clone()))
+            .and(registration_request(verifier, "mesh-key".to_string()))
+            .and(limit_globally(limiter))
+            .map(|_permit: RegistrationPermit, _request: NodeRequest| StatusCode::ACCEPTED)
+            .recover(recover)
+            .recover(signing::recover);
+
+        let register = |auth: &str| warp::test::request()
+            .method("POST")
+            .path("/register/10.0.0.1")
+            .body(format!("{{\"auth\":\"{}\"}}", auth));
+
+        // Turned away before the global quota is reached, so it is left for the real node.
+        assert_eq!(register("guess").reply(&route).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(register("mesh-key").reply(&route).await.status(), StatusCode::ACCEPTED);
+
+        let limited = register("mesh-key").reply(&route).await;
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(limited.headers().contains_key("Retry-After"));
+    }
+
+    #[tokio::test]
+    async fn in_flight_slots_are_freed_with_their_permit() {
+        let limiter = RegistrationLimiter::new(RateLimits { window: LENGTH, per_source: 10, global: 10, in_flight: 1 });
+
+        let permit = limiter.admit("10.0.0.1".to_string()).await.unwrap();
+        assert!(matches!(limiter.admit("10.0.0.2".to_string()).await, Err(RateLimited { scope: "in_flight", .. })));
+
+        drop(permit);
+        assert!(limiter.admit("10.0.0.2".to_string()).await.is_ok());
+    }
+}
@@ -0,0 +1,167 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use rand::Rng;
+
+use crate::models::{HealthCheckSettings, Task, TaskType, Tries};
+
+/// When, and how often, a task is retried after a failed attempt.
+///
+/// The delay before retry `n` is `base * 2^n`, capped at `max_delay`, of which a random half is
+/// taken off again so nodes which failed together (e.g. during a Cloudflare or MySQL outage) do not retry together.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub base: Duration,
+    pub max_delay: Duration,
+    /// Attempts made before the task is given up on, `None` to retry forever.
+    pub max_attempts: Option<Tries>
+}
+
+impl RetryPolicy {
+    /// Whether a task which has already been attempted `tries` times should be given up on.
+    pub fn exhausted(&self, tries: Tries) -> bool {
+        match self.max_attempts {
+            Some(max_attempts) => tries >= max_attempts,
+            None => false
+        }
+    }
+
+    /// The delay before the attempt following `tries` failed attempts.
+    pub fn delay(&self, tries: Tries) -> Duration {
+        let exponent = tries.clamp(0, 16) as u32;
+        let ceiling = self.base.saturating_mul(2u32.pow(exponent)).min(self.max_delay);
+
+        let half = ceiling / 2;
+        let jitter = rand::thread_rng().gen_range(0..=half.as_millis() as u64);
+
+        half + Duration::from_millis(jitter)
+    }
+
+    /// The `exec_at` of the attempt following `tries` failed attempts.
+    pub fn next_attempt(&self, tries: Tries) -> u128 {
+        Utc::now().timestamp_millis() as u128 + self.delay(tries).as_millis()
+    }
+
+    /// The task to queue after an attempt at `task` failed with `error`, counting the attempt and delayed by the backoff.
+    pub fn retry(&self, task: &Task, error: String) -> Task {
+        Task {
+            task_type: task.task_type.retried(),
+            action_object: task.action_object.clone(),
+            exec_at: self.next_attempt(task.task_type.tries()),
+            generation: task.generation,
+            last_error: Some(error)
+        }
+    }
+}
+
+/// The retry policy of each task type, read from the environment by `retry_policies`.
+#[derive(Clone, Debug)]
+pub struct RetryPolicies {
+    pub check_status: RetryPolicy,
+    pub instantiate: RetryPolicy,
+    pub dismiss: RetryPolicy,
+    pub recover: RetryPolicy,
+    pub purge: RetryPolicy
+}
+
+impl RetryPolicies {
+    pub fn for_task(&self, task_type: &TaskType) -> &RetryPolicy {
+        match task_type {
+            TaskType::CheckStatus => &self.check_status,
+            TaskType::Instantiate(_) => &self.instantiate,
+            TaskType::Dismiss(_) => &self.dismiss,
+            TaskType::Recover => &self.recover,
+            TaskType::Purge(_) => &self.purge
+        }
+    }
+}
+
+/// Default policies. A failing health check is retried sooner than the regular check interval, but never given up on
+/// by its policy; the node is dismissed once it has failed `dismiss_after` checks in a row instead. A dismissed node is
+/// probed at the recovery interval.
+pub fn default_policies(health_checks: &HealthCheckSettings) -> RetryPolicies {
+    RetryPolicies {
+        check_status: RetryPolicy {
+            base: Duration::from_secs(1),
+            max_delay: health_checks.interval,
+            max_attempts: None
+        },
+        instantiate: RetryPolicy {
+            base: Duration::from_secs(5),
+            max_delay: Duration::from_secs(60),
+            max_attempts: Some(6)
+        },
+        dismiss: RetryPolicy {
+            base: Duration::from_secs(5),
+            max_delay: Duration::from_secs(300),
+            max_attempts: Some(6)
+        },
+        recover: RetryPolicy {
+            base: health_checks.recovery_interval,
+            max_delay: health_checks.recovery_interval,
+            max_attempts: None
+        },
+        purge: RetryPolicy {
+            base: Duration::from_secs(30),
+            max_delay: Duration::from_secs(600),
+            max_attempts: Some(6)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy { base: Duration::from_secs(5), max_delay: Duration::from_secs(60), max_attempts: Some(6) }
+    }
+
+    #[test]
+    fn delay_doubles_within_half_of_its_ceiling() {
+        let policy = policy();
+
+        for (tries, ceiling) in [(0, 5), (1, 10), (2, 20), (3, 40)] {
+            let ceiling = Duration::from_secs(ceiling);
+
+            for _ in 0..50 {
+                let delay = policy.delay(tries);
+                assert!(delay >= ceiling / 2 && delay <= ceiling, "try {} waited {:?}", tries, delay);
+            }
+        }
+    }
+
+    #[test]
+    fn delay_is_capped() {
+        let policy = policy();
+
+        for tries in [4, 16, 100, Tries::MAX, -1] {
+            assert!(policy.delay(tries) <= policy.max_delay);
+        }
+    }
+
+    #[test]
+    fn exhausted_after_max_attempts() {
+        assert!(!policy().exhausted(5));
+        assert!(policy().exhausted(6));
+        assert!(!RetryPolicy { max_attempts: None, ..policy() }.exhausted(Tries::MAX));
+    }
+
+    #[test]
+    fn retry_counts_the_attempt() {
+        let task = Task {
+            task_type: TaskType::Purge(2),
+            action_object: "10.0.0.1".to_string(),
+            exec_at: 0,
+            generation: 1,
+            last_error: None
+        };
+
+        let retried = policy().retry(&task, "certificate: unable to reach provider".to_string());
+
+        assert_eq!(retried.task_type.tries(), 3);
+        assert_eq!(retried.generation, 1);
+        assert_eq!(retried.last_error.as_deref(), Some("certificate: unable to reach provider"));
+        assert!(retried.exec_at > Utc::now().timestamp_millis() as u128);
+    }
+}
@@ -1,7 +1,93 @@
-use warp::{self, Filter};
+use std::net::SocketAddr;
 
-use crate::models::{Server};
+use tracing::warn;
+use warp::{self, Filter, Rejection};
+use warp::http::Method;
+use warp::hyper::body::Bytes;
+use warp::path::FullPath;
 
-pub fn json_body() -> impl Filter<Extract = (Server,), Error = warp::Rejection> + Clone {
-    warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+use crate::metrics::REGISTRATIONS;
+use crate::models::Server;
+use crate::mtls::{PeerCertificate, RemoteAddress};
+use crate::signing::{NodeRequest, RequestRejected, RequestVerifier, NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+
+/// Reads the body of a node request, verifying its signature when it carries one.
+/// An empty body is read as a `Server` without any fields set, for requests authenticated by signature or certificate alone.
+pub fn node_request(
+    verifier: RequestVerifier
+) -> impl Filter<Extract = (NodeRequest,), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>(TIMESTAMP_HEADER))
+        .and(warp::header::optional::<String>(NONCE_HEADER))
+        .and(warp::header::optional::<String>(SIGNATURE_HEADER))
+        .and(body())
+        .and(warp::ext::optional::<PeerCertificate>())
+        .and(warp::any().map(move || verifier.clone()))
+        .and_then(|method: Method, path: FullPath, timestamp: Option<String>, nonce: Option<String>, signature: Option<String>, body: Bytes, certificate: Option<PeerCertificate>, verifier: RequestVerifier| async move {
+            let signed = match (timestamp, nonce, signature) {
+                (Some(timestamp), Some(nonce), Some(signature)) => {
+                    if let Err(err) = verifier.verify(&method, path.as_str(), &timestamp, &nonce, &signature, &body).await {
+                        warn!(path = path.as_str(), error = err.code(), "Rejected signed node request");
+                        return Err(warp::reject::custom(err))
+                    }
+
+                    true
+                },
+                // A client certificate may stand in for the signature, the handler checks it belongs to the node.
+                (None, None, None) if verifier.required && certificate.is_none() => return Err(warp::reject::custom(RequestRejected::Unsigned)),
+                (None, None, None) => false,
+                _ => return Err(warp::reject::custom(RequestRejected::Malformed(format!("signed requests need {}, {} and {}", TIMESTAMP_HEADER, NONCE_HEADER, SIGNATURE_HEADER))))
+            };
+
+            let body = match body.is_empty() {
+                true => Server::default(),
+                false => serde_json::from_slice::<Server>(&body)
+                    .map_err(|err| warp::reject::custom(RequestRejected::Malformed(err.to_string())))?
+            };
+
+            Ok(NodeRequest { body, signed, key_accepted: !verifier.required, certificate })
+        })
+}
+
+/// A `node_request` which is signed or carries the mesh authentication key `check_key`, as registering requires.
+/// Any other is rejected with `RequestRejected::Forbidden`.
+pub fn registration_request(
+    verifier: RequestVerifier,
+    check_key: String
+) -> impl Filter<Extract = (NodeRequest,), Error = Rejection> + Clone {
+    node_request(verifier)
+        .and(warp::any().map(move || check_key.clone()))
+        .and_then(|request: NodeRequest, check_key: String| async move {
+            match request.authorized(&check_key) {
+                true => Ok(request),
+                false => {
+                    REGISTRATIONS.with_label_values(&["forbidden"]).inc();
+                    Err(warp::reject::custom(RequestRejected::Forbidden))
+                }
+            }
+        })
+}
+
+/// The body of a request, up to 16 KiB. A request without a `Content-Length`, such as a signed `GET`, has an empty body.
+fn body() -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    let sized = warp::body::content_length_limit(1024 * 16)
+        .and(warp::body::bytes());
+
+    let empty = warp::header::optional::<u64>("content-length")
+        .and_then(|length: Option<u64>| async move {
+            match length {
+                None => Ok(Bytes::new()),
+                Some(_) => Err(warp::reject())
+            }
+        });
+
+    sized.or(empty).unify()
+}
+
+/// The address of the client, whether warp is serving the connection itself or `mtls::serve_mutual` is.
+pub fn remote_address() -> impl Filter<Extract = (Option<SocketAddr>,), Error = std::convert::Infallible> + Clone {
+    warp::ext::optional::<RemoteAddress>()
+        .and(warp::addr::remote())
+        .map(|injected: Option<RemoteAddress>, remote: Option<SocketAddr>| injected.map(|addr| addr.0).or(remote))
 }
\ No newline at end of file
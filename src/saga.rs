@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use futures_timer::Delay;
+use reqwest::Client;
+use tracing::{error, info, warn};
+
+use crate::audit::{record, AuditEntry, AuditLog, MESH_ACTOR};
+use crate::cloudflare::{delete_dns_record, revoke_certificate};
+
+/// Number of times a single compensating action is attempted before it is abandoned.
+const COMPENSATION_ATTEMPTS: u8 = 3;
+
+/// The undo action for a completed registration step.
+#[derive(Debug, Clone)]
+pub enum Compensation {
+    /// Remove a DNS record created for the node.
+    DeleteDnsRecord(String),
+    /// Revoke the origin certificate issued to the node.
+    RevokeCertificate(String)
+}
+
+impl Compensation {
+    /// The resource the compensation removes, as recorded in the audit log.
+    fn resource(&self) -> String {
+        match self {
+            Compensation::DeleteDnsRecord(id) => format!("dns record {}", id),
+            Compensation::RevokeCertificate(id) => format!("certificate {}", id)
+        }
+    }
+}
+
+/// Registration is performed as a sequence of steps (DNS A record, `.dns` record, certificate, database insert),
+/// each of which creates a resource outside of the mesh. As each step completes its compensating action is recorded,
+/// so that if a later step fails every resource created so far can be removed in reverse order.
+/// Creation and removal of each resource is written to the audit log.
+#[derive(Debug)]
+pub struct RegistrationSaga {
+    completed: Vec<Compensation>,
+    node_id: Option<String>,
+    ip: String,
+    audit: AuditLog
+}
+
+impl RegistrationSaga {
+    pub fn new(ip: String, audit: AuditLog) -> Self {
+        RegistrationSaga { completed: vec![], node_id: None, ip, audit }
+    }
+
+    /// Sets the identifier of the node being provisioned, once it is known.
+    pub fn identify(&mut self, node_id: &str) {
+        self.node_id = Some(node_id.to_string());
+    }
+
+    fn audit(&self, action: &str, detail: String) {
+        record(&self.audit, AuditEntry {
+            node_id: self.node_id.clone(),
+            ip: Some(self.ip.clone()),
+            ..AuditEntry::new(MESH_ACTOR, action, detail)
+        });
+    }
+
+    /// Records a completed step.
+    pub fn completed(&mut self, compensation: Compensation) {
+        self.audit("resource_created", compensation.resource());
+        self.completed.push(compensation);
+    }
+
+    /// Runs every recorded compensating action, most recent first.
+    /// Failures are retried a few times and then logged, a failing action does not stop the remaining ones.
+    pub async fn rollback(mut self, cloudflare_key: &String, client: &Client) {
+        let completed = std::mem::take(&mut self.completed);
+
+        for compensation in completed.into_iter().rev() {
+            let mut attempt = 0;
+
+            loop {
+                attempt += 1;
+
+                let result = match &compensation {
+                    Compensation::DeleteDnsRecord(id) => {
+                        delete_dns_record(cloudflare_key, client, id).await.map_err(|err| err.to_string())
+                    },
+                    Compensation::RevokeCertificate(id) => {
+                        revoke_certificate(cloudflare_key, client, id).await.map_err(|err| err.to_string())
+                    },
+                };
+
+                match result {
+                    Ok(_) => {
+                        info!(compensation = ?compensation, "Rollback->Complete");
+                        self.audit("resource_deleted", format!("{}, registration rolled back", compensation.resource()));
+                        break;
+                    },
+                    Err(err) if attempt < COMPENSATION_ATTEMPTS => {
+                        warn!(compensation = ?compensation, attempt, error = %err, "Rollback->Failed, Retrying");
+                        Delay::new(Duration::from_secs(1)).await;
+                    },
+                    Err(err) => {
+                        error!(compensation = ?compensation, attempt, error = %err, "Rollback->Abandoned");
+                        self.audit("resource_delete_failed", format!("{}, {}", compensation.resource(), err));
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
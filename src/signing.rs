@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::sync::Mutex;
+use warp::http::{Method, StatusCode};
+use warp::reply::{json as json_reply, with_status};
+use warp::{Rejection, Reply};
+
+use crate::errors::ErrorReply;
+use crate::models::{Node, Server};
+use crate::mtls::{issued_to, PeerCertificate};
+
+pub const TIMESTAMP_HEADER: &str = "x-reseda-timestamp";
+pub const NONCE_HEADER: &str = "x-reseda-nonce";
+pub const SIGNATURE_HEADER: &str = "x-reseda-signature";
+
+/// Seconds a signed request's timestamp may be away from the mesh clock. Nonces are remembered for twice as long,
+/// by which point a replay would be turned away for its timestamp anyway.
+const MAX_SKEW: i64 = 300;
+
+/// Checks the signature of node requests and remembers the nonces of those already accepted.
+///
+/// A signed request carries `X-Reseda-Timestamp` (unix seconds), a unique `X-Reseda-Nonce` and
+/// `X-Reseda-Signature`, the hex HMAC-SHA256 keyed with the mesh authentication key of:
+///
+/// ```text
+/// <METHOD>\n<path>\n<timestamp>\n<nonce>\n<body>
+/// ```
+///
+/// so the key itself never travels with the request, unlike the `auth` field of unsigned requests.
+#[derive(Clone, Debug)]
+pub struct RequestVerifier {
+    key: String,
+    /// Turns away unsigned requests once every node signs, see `required`.
+    pub required: bool,
+    nonces: Arc<Mutex<HashMap<String, i64>>>
+}
+
+/// The body of a node request, and whether it was signed.
+#[derive(Debug)]
+pub struct NodeRequest {
+    pub body: Server,
+    pub signed: bool,
+    /// Whether the `auth` field is still accepted, it is not once signatures are required.
+    pub key_accepted: bool,
+    /// The certificate presented over mutual TLS, if any.
+    pub certificate: Option<PeerCertificate>
+}
+
+impl NodeRequest {
+    /// Whether the request was signed, or carries the mesh authentication key in its `auth` field.
+    pub fn authorized(&self, key: &String) -> bool {
+        self.signed || (self.key_accepted && &self.body.auth == key)
+    }
+
+    /// Whether the request was made with the certificate currently issued to `node`.
+    pub fn certifies(&self, node: &Node) -> bool {
+        match &self.certificate {
+            Some(certificate) => issued_to(certificate, node),
+            None => false
+        }
+    }
+}
+
+/// A node request turned away by `routes::node_request`.
+#[derive(Debug)]
+pub enum RequestRejected {
+    Malformed(String),
+    Unsigned,
+    Expired,
+    Replayed,
+    InvalidSignature,
+    /// Neither signed nor carrying the mesh authentication key, where the route requires either.
+    Forbidden
+}
+
+impl warp::reject::Reject for RequestRejected {}
+
+impl RequestRejected {
+    pub fn code(&self) -> &'static str {
+        match self {
+            RequestRejected::Malformed(_) => "malformed_request",
+            RequestRejected::Unsigned => "signature_required",
+            RequestRejected::Expired => "signature_expired",
+            RequestRejected::Replayed => "nonce_reused",
+            RequestRejected::InvalidSignature => "signature_invalid",
+            RequestRejected::Forbidden => "forbidden"
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            RequestRejected::Malformed(reason) => format!("malformed request: {}", reason),
+            RequestRejected::Unsigned => "requests must be signed".to_string(),
+            RequestRejected::Expired => format!("timestamp is more than {} seconds away from the mesh clock", MAX_SKEW),
+            RequestRejected::Replayed => "nonce has already been used".to_string(),
+            RequestRejected::InvalidSignature => "signature does not match the request".to_string(),
+            RequestRejected::Forbidden => "request must be signed or carry the mesh authentication key".to_string()
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            RequestRejected::Malformed(_) => StatusCode::BAD_REQUEST,
+            RequestRejected::Forbidden => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED
+        }
+    }
+}
+
+impl RequestVerifier {
+    pub fn new(key: String, required: bool) -> Self {
+        RequestVerifier { key, required, nonces: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub async fn verify(
+        &self,
+        method: &Method,
+        path: &str,
+        timestamp: &str,
+        nonce: &str,
+        signature: &str,
+        body: &[u8]
+    ) -> Result<(), RequestRejected> {
+        let timestamp = timestamp.parse::<i64>()
+            .map_err(|_| RequestRejected::Malformed(format!("{} must be unix seconds", TIMESTAMP_HEADER)))?;
+        let signature = hex::decode(signature)
+            .map_err(|_| RequestRejected::Malformed(format!("{} must be hex encoded", SIGNATURE_HEADER)))?;
+
+        let now = Utc::now().timestamp();
+
+        if (now - timestamp).abs() > MAX_SKEW {
+            return Err(RequestRejected::Expired)
+        }
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.as_bytes())
+            .expect("HMAC accepts keys of any length");
+
+        mac.update(format!("{}\n{}\n{}\n{}\n", method, path, timestamp, nonce).as_bytes());
+        mac.update(body);
+
+        mac.verify_slice(&signature).map_err(|_| RequestRejected::InvalidSignature)?;
+
+        // Only remembered once the signature holds, so unsigned traffic cannot fill the cache.
+        let mut nonces = self.nonces.lock().await;
+        nonces.retain(|_, seen| now - *seen <= MAX_SKEW * 2);
+
+        if nonces.contains_key(nonce) {
+            return Err(RequestRejected::Replayed)
+        }
+
+        nonces.insert(nonce.to_string(), now);
+        Ok(())
+    }
+}
+
+/// Whether unsigned node requests are turned away. Set `REQUIRE_SIGNED_REQUESTS=true` once every node signs its requests.
+/// The `auth` field is then no longer accepted, requests made with a node certificate over mutual TLS still are.
+pub fn required() -> bool {
+    match env::var("REQUIRE_SIGNED_REQUESTS") {
+        Ok(val) => val.eq_ignore_ascii_case("true"),
+        Err(_) => false
+    }
+}
+
+/// Answers a `RequestRejected` rejection with its status and a JSON error, any other rejection is passed on untouched.
+pub async fn recover(rejection: Rejection) -> Result<Box<dyn Reply>, Rejection> {
+    match rejection.find::<RequestRejected>() {
+        Some(rejected) => {
+            let body = ErrorReply::new(rejected.code(), rejected.message());
+
+            Ok(Box::new(with_status(json_reply(&body), rejected.status())))
+        },
+        None => Err(rejection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::node_request;
+
+    const KEY: &str = "mesh-key";
+
+    fn sign(method: &str, path: &str, timestamp: i64, nonce: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(KEY.as_bytes()).unwrap();
+
+        mac.update(format!("{}\n{}\n{}\n{}\n", method, path, timestamp, nonce).as_bytes());
+        mac.update(body);
+
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    async fn verify(verifier: &RequestVerifier, timestamp: i64, nonce: &str, signature: &str, body: &[u8]) -> Result<(), RequestRejected> {
+        verifier.verify(&Method::POST, "/register/10.0.0.1", &timestamp.to_string(), nonce, signature, body).await
+    }
+
+    #[tokio::test]
+    async fn accepts_a_valid_signature() {
+        let verifier = RequestVerifier::new(KEY.to_string(), true);
+        let now = Utc::now().timestamp();
+        let signature = sign("POST", "/register/10.0.0.1", now, "n1", b"{}");
+
+        assert!(verify(&verifier, now, "n1", &signature, b"{}").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_a_tampered_body() {
+        let verifier = RequestVerifier::new(KEY.to_string(), true);
+        let now = Utc::now().timestamp();
+        let signature = sign("POST", "/register/10.0.0.1", now, "n1", b"{}");
+
+        assert!(matches!(verify(&verifier, now, "n1", &signature, b"{\"rotate\":true}").await, Err(RequestRejected::InvalidSignature)));
+    }
+
+    #[tokio::test]
+    async fn rejects_timestamps_past_the_skew() {
+        let verifier = RequestVerifier::new(KEY.to_string(), true);
+
+        for timestamp in [Utc::now().timestamp() - MAX_SKEW - 5, Utc::now().timestamp() + MAX_SKEW + 5] {
+            let signature = sign("POST", "/register/10.0.0.1", timestamp, "n1", b"");
+
+            assert!(matches!(verify(&verifier, timestamp, "n1", &signature, b"").await, Err(RequestRejected::Expired)));
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_a_replayed_nonce() {
+        let verifier = RequestVerifier::new(KEY.to_string(), true);
+        let now = Utc::now().timestamp();
+        let signature = sign("POST", "/register/10.0.0.1", now, "n1", b"");
+
+        assert!(verify(&verifier, now, "n1", &signature, b"").await.is_ok());
+        assert!(matches!(verify(&verifier, now, "n1", &signature, b"").await, Err(RequestRejected::Replayed)));
+    }
+
+    #[tokio::test]
+    async fn does_not_remember_nonces_of_invalid_signatures() {
+        let verifier = RequestVerifier::new(KEY.to_string(), true);
+        let now = Utc::now().timestamp();
+
+        assert!(verify(&verifier, now, "n1", "00", b"").await.is_err());
+        assert!(verify(&verifier, now, "n1", &sign("POST", "/register/10.0.0.1", now, "n1", b""), b"").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_headers() {
+        let verifier = RequestVerifier::new(KEY.to_string(), true);
+
+        assert!(matches!(verifier.verify(&Method::POST, "/", "yesterday", "n1", "00", b"").await, Err(RequestRejected::Malformed(_))));
+        assert!(matches!(verifier.verify(&Method::POST, "/", "0", "n1", "not hex", b"").await, Err(RequestRejected::Malformed(_))));
+    }
+
+    async fn rejection(verifier: RequestVerifier, headers: &[(&str, String)]) -> Option<&'static str> {
+        let mut request = warp::test::request().method("POST").path("/register/10.0.0.1").body("");
+
+        for (name, value) in headers {
+            request = request.header(*name, value.as_str());
+        }
+
+        match request.filter(&node_request(verifier)).await {
+            Ok(_) => None,
+            Err(rejection) => Some(rejection.find::<RequestRejected>().unwrap().code())
+        }
+    }
+
+    #[tokio::test]
+    async fn requires_every_signature_header() {
+        let now = Utc::now().timestamp();
+        let signature = sign("POST", "/register/10.0.0.1", now, "n1", b"");
+
+        let partial = [
+            (TIMESTAMP_HEADER, now.to_string()),
+            (NONCE_HEADER, "n1".to_string())
+        ];
+        assert_eq!(rejection(RequestVerifier::new(KEY.to_string(), false), &partial).await, Some("malformed_request"));
+
+        let complete = [
+            (TIMESTAMP_HEADER, now.to_string()),
+            (NONCE_HEADER, "n1".to_string()),
+            (SIGNATURE_HEADER, signature)
+        ];
+        assert_eq!(rejection(RequestVerifier::new(KEY.to_string(), true), &complete).await, None);
+    }
+
+    #[tokio::test]
+    async fn accepts_a_signed_get_without_a_body() {
+        let now = Utc::now().timestamp();
+
+        let request = warp::test::request()
+            .method("GET")
+            .path("/register/jobs/job")
+            .header(TIMESTAMP_HEADER, now.to_string())
+            .header(NONCE_HEADER, "n1")
+            .header(SIGNATURE_HEADER, sign("GET", "/register/jobs/job", now, "n1", b""));
+
+        assert!(request.filter(&node_request(RequestVerifier::new(KEY.to_string(), true))).await.unwrap().signed);
+    }
+
+    #[tokio::test]
+    async fn unsigned_requests_only_pass_while_signatures_are_optional() {
+        assert_eq!(rejection(RequestVerifier::new(KEY.to_string(), false), &[]).await, None);
+        assert_eq!(rejection(RequestVerifier::new(KEY.to_string(), true), &[]).await, Some("signature_required"));
+    }
+}
@@ -1,33 +1,53 @@
 use dotenv::dotenv;
 use rcgen::generate_simple_self_signed;
-use sqlx::{Pool, MySql, mysql::MySqlPoolOptions};
+use sqlx::{Any, Pool, any::AnyPoolOptions};
 use std::collections::{HashMap, VecDeque};
-use std::{env, sync::Arc};
+use std::{env, sync::Arc, time::Duration};
 use std::fs::File;
 use std::io::Write;
 use tokio::sync::Mutex;
 use reqwest::Client;
+use tracing::{debug, error, info, warn};
 
-use crate::models::TaskQueue;
+use crate::audit::{spawn_writer as spawn_audit_writer, AuditLog};
+use crate::directory::{for_pool, spawn_load_sync, Directory, LoadTally};
+use crate::migrate;
+use crate::rate_limit::{RateLimits, RegistrationLimiter};
+use crate::signing::{required as signatures_required, RequestVerifier};
+use crate::events::{event_bus, EventBus};
+use crate::history::{spawn_recorder, HealthTally};
+use crate::models::{TaskQueue, JobStore, HealthCheckSettings};
+use crate::retry::{default_policies, RetryPolicies, RetryPolicy};
+use crate::webhooks::{load_targets, spawn_dispatcher, DeliveryLog};
 use crate::{models::{Configuration, Stack}, models::CloudflareReturn};
 
 #[derive(Clone)]
 pub struct MeshState {
     pub keys: Configuration,
-    pub pool: Pool<MySql>,
+    pub pool: Pool<Any>,
+    pub directory: Directory,
     pub client: Client,
+    /// Sends health checks, with `health_checks.timeout` so an unresponsive node fails its check rather than hanging it.
+    pub health_client: Client,
 
     pub instance_stack: Stack,
-    pub task_queue: TaskQueue
+    pub task_queue: TaskQueue,
+    pub jobs: JobStore,
+    pub events: EventBus,
+    pub webhook_deliveries: DeliveryLog,
+    pub audit: AuditLog,
+    pub health: HealthTally,
+    pub load: LoadTally,
+    pub health_checks: HealthCheckSettings,
+    pub retries: RetryPolicies,
+    pub registrations: RegistrationLimiter,
+    pub signing: RequestVerifier
 }
 
 pub fn with_environment() -> Configuration {
     dotenv().expect(".env file not found");
 
-    println!("Environment Keys:");
-    for argument in env::args() {
-        println!("{}", argument);
-    }
+    debug!(arguments = ?env::args().skip(1).collect::<Vec<String>>(), "Loading environment");
 
     let authentication = match env::var("AUTHENTICATION_KEY") {
         Ok(val) => val,
@@ -49,22 +69,142 @@ pub fn with_environment() -> Configuration {
     }
 }
 
+/// Reads the health check settings, each of which is optional:
+///
+/// - `HEALTH_CHECK_INTERVAL` seconds between checks of a published node, defaults to 5.
+/// - `HEALTH_DEGRADE_AFTER` consecutive failures before a node is degraded, defaults to 3.
+/// - `HEALTH_DISMISS_AFTER` consecutive failures before a node is dismissed, defaults to 12.
+/// - `HEALTH_RECOVER_AFTER` consecutive successes before a node is published again, defaults to 3.
+/// - `HEALTH_RECOVERY_INTERVAL` seconds between probes of a dismissed node, defaults to 30.
+/// - `HEALTH_CHECK_TIMEOUT` seconds a node has to answer a check, defaults to 10.
+pub fn health_check_settings() -> HealthCheckSettings {
+    let settings = HealthCheckSettings {
+        interval: Duration::from_secs(numeric_variable("HEALTH_CHECK_INTERVAL", 5)),
+        degrade_after: numeric_variable("HEALTH_DEGRADE_AFTER", 3) as u32,
+        dismiss_after: numeric_variable("HEALTH_DISMISS_AFTER", 12) as u32,
+        recover_after: numeric_variable("HEALTH_RECOVER_AFTER", 3) as u32,
+        recovery_interval: Duration::from_secs(numeric_variable("HEALTH_RECOVERY_INTERVAL", 30)),
+        timeout: Duration::from_secs(numeric_variable("HEALTH_CHECK_TIMEOUT", 10))
+    };
+
+    if settings.degrade_after == 0 || settings.recover_after == 0 || settings.dismiss_after < settings.degrade_after {
+        panic!("[err]: Health check thresholds must be non-zero, and $HEALTH_DISMISS_AFTER no lower than $HEALTH_DEGRADE_AFTER.");
+    }
+
+    if settings.timeout.is_zero() {
+        panic!("[err]: $HEALTH_CHECK_TIMEOUT must be non-zero.");
+    }
+
+    info!(settings = ?settings, "Loaded health check settings");
+    settings
+}
+
+/// Reads the retry policy of each task type. Every setting is optional and defaults to `default_policies`:
+///
+/// - `RETRY_<TASK>_BASE` seconds before the first retry, doubled on each further failure.
+/// - `RETRY_<TASK>_MAX_DELAY` seconds the delay is capped at.
+/// - `RETRY_<TASK>_ATTEMPTS` attempts made before giving up, `0` to retry forever.
+///
+/// Where `<TASK>` is one of `CHECK_STATUS`, `INSTANTIATE`, `DISMISS`, `RECOVER` or `PURGE`. `RETRY_CHECK_STATUS_ATTEMPTS`
+/// is ignored, a node failing its health checks is dismissed after `HEALTH_DISMISS_AFTER` of them.
+pub fn retry_policies(health_checks: &HealthCheckSettings) -> RetryPolicies {
+    let defaults = default_policies(health_checks);
+
+    if env::var("RETRY_CHECK_STATUS_ATTEMPTS").is_ok() {
+        warn!("$RETRY_CHECK_STATUS_ATTEMPTS is ignored, set $HEALTH_DISMISS_AFTER to change when failing nodes are dismissed.");
+    }
+
+    let policies = RetryPolicies {
+        check_status: RetryPolicy {
+            max_attempts: None,
+            ..retry_policy("CHECK_STATUS", defaults.check_status)
+        },
+        instantiate: retry_policy("INSTANTIATE", defaults.instantiate),
+        dismiss: retry_policy("DISMISS", defaults.dismiss),
+        recover: retry_policy("RECOVER", defaults.recover),
+        purge: retry_policy("PURGE", defaults.purge)
+    };
+
+    info!(policies = ?policies, "Loaded task retry policies");
+    policies
+}
+
+fn retry_policy(task: &str, default: RetryPolicy) -> RetryPolicy {
+    let attempts = numeric_variable(&format!("RETRY_{}_ATTEMPTS", task), default.max_attempts.unwrap_or(0) as u64);
+
+    RetryPolicy {
+        base: Duration::from_secs(numeric_variable(&format!("RETRY_{}_BASE", task), default.base.as_secs())),
+        max_delay: Duration::from_secs(numeric_variable(&format!("RETRY_{}_MAX_DELAY", task), default.max_delay.as_secs())),
+        max_attempts: if attempts == 0 { None } else { Some(attempts.min(i16::MAX as u64) as i16) }
+    }
+}
+
+/// Reads the limits applied to registrations, each of which is optional:
+///
+/// - `REGISTER_RATE_WINDOW` seconds the quotas below are counted over, defaults to 60.
+/// - `REGISTER_RATE_PER_SOURCE` registrations accepted per source address per window, defaults to 5.
+/// - `REGISTER_RATE_GLOBAL` registrations accepted from all sources per window, defaults to 60.
+/// - `REGISTER_MAX_IN_FLIGHT` registrations provisioned at once, defaults to 16.
+pub fn registration_limits() -> RateLimits {
+    let limits = RateLimits {
+        window: Duration::from_secs(numeric_variable("REGISTER_RATE_WINDOW", 60)),
+        per_source: numeric_variable("REGISTER_RATE_PER_SOURCE", 5).min(u32::MAX as u64) as u32,
+        global: numeric_variable("REGISTER_RATE_GLOBAL", 60).min(u32::MAX as u64) as u32,
+        in_flight: numeric_variable("REGISTER_MAX_IN_FLIGHT", 16) as usize
+    };
+
+    if limits.window.is_zero() || limits.per_source == 0 || limits.global == 0 || limits.in_flight == 0 {
+        panic!("[err]: Registration limits must be non-zero.");
+    }
+
+    info!(limits = ?limits, "Loaded registration limits");
+    limits
+}
+
+/// Reads `DIRECTORY_SYNC_INTERVAL`, the seconds between writes of the nodes' load to the server directory, defaults to 30.
+pub fn directory_sync_interval() -> Duration {
+    match numeric_variable("DIRECTORY_SYNC_INTERVAL", 30) {
+        0 => panic!("[err]: Environment variable: $DIRECTORY_SYNC_INTERVAL must be at least 1."),
+        seconds => Duration::from_secs(seconds)
+    }
+}
+
+fn numeric_variable(name: &str, default: u64) -> u64 {
+    match env::var(name) {
+        Ok(val) => match val.parse::<u64>() {
+            Ok(val) => val,
+            Err(_) => panic!("[err]: Environment variable: ${} must be a whole number, got {}.", name, val)
+        },
+        Err(_) => default
+    }
+}
+
+/// Connects to `$DATABASE_URL`, whose scheme selects the database (`mysql://...`, `postgres://...` or `sqlite:...`).
+pub async fn connect(config: &Configuration) -> Pool<Any> {
+    match AnyPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.database_key).await {
+            Ok(pool) => {
+                info!("sqlx::success Successfully started pool.");
+                pool
+            },
+            Err(error) => {
+                panic!("[service] sqlx::error Failed to initialize SQLX pool. Reason: {}", error);
+            }
+        }
+}
+
 impl MeshState {
     pub async fn initialize() -> Self {
         let client = reqwest::Client::new();
         let config = with_environment();
+        let pool = connect(&config).await;
 
-        let pool = match MySqlPoolOptions::new()
-                .max_connections(5)
-                .connect(&config.database_key).await {
-                    Ok(pool) => {
-                        println!("[service] sqlx::success Successfully started pool.");
-                        pool
-                    },
-                    Err(error) => {
-                        panic!("[service] sqlx::error Failed to initialize SQLX pool. Reason: {}", error);
-                    }
-            };
+        if migrate::on_start() {
+            if let Err(error) = migrate::run(&pool).await {
+                panic!("[service] sqlx::error Failed to apply database migrations. Reason: {}", error);
+            }
+        }
 
         let cert = generate_simple_self_signed(vec![format!("mesh.reseda.app")]).unwrap();
         let cert_public = cert.serialize_request_pem().unwrap();
@@ -85,16 +225,16 @@ impl MeshState {
             .header("Authorization", format!("Bearer {}", config.cloudflare_key))
             .send().await {
                 Ok(response) => {
-                    // println!("{:?}", response.text().await);
                     let r = response.json::<CloudflareReturn>().await;
 
                     match r {
                         Ok(return_value) => {
-                            if return_value.success == false {
-                                println!("[err]: cloudflare certificate creation FAILED, return value FAILURE. Reason: {:?}", return_value);
+                            match return_value.into_result() {
+                                Ok(result) => (result.certificate, cert_private),
+                                Err(err) => {
+                                    panic!("[err]: Cloudflare certificate creation for mesh.reseda.app failed, {}", err)
+                                }
                             }
-
-                            (return_value.result.certificate, cert_private)
                         },
                         Err(err) => {
                             panic!("[err]: Deserializing Cloudflare Result: {}", err)
@@ -111,12 +251,12 @@ impl MeshState {
                 match write!(output, "{}", key) {
                     Ok(_) => {},
                     Err(err) => {
-                        println!("[err]: Unable to write file file::key.pem; {}", err);
+                        error!(error = %err, "Unable to write file file::key.pem");
                     },
                 }
             },
             Err(err) => {
-                println!("[err]: Unable to open file stream for file::key.pem; {}", err)
+                error!(error = %err, "Unable to open file stream for file::key.pem")
             },
         };
 
@@ -125,23 +265,50 @@ impl MeshState {
                 match write!(output, "{}", cert) {
                     Ok(_) => {},
                     Err(err) => {
-                        println!("[err]: Unable to write file file::cert.pem; {}", err);
+                        error!(error = %err, "Unable to write file file::cert.pem");
                     },
                 }
             },
             Err(err) => {
-                println!("[err]: Unable to open file stream for file::cert.pem; {}", err)
+                error!(error = %err, "Unable to open file stream for file::cert.pem")
             },
         };
 
+        let events = event_bus();
+        let webhook_deliveries: DeliveryLog = Arc::new(Mutex::new(VecDeque::new()));
+
+        spawn_dispatcher(&events, load_targets(), client.clone(), webhook_deliveries.clone());
+        let health_checks = health_check_settings();
+        let health_client = Client::builder()
+            .timeout(health_checks.timeout)
+            .build()
+            .expect("[err]: Unable to build the health check client.");
+        let audit = spawn_audit_writer(pool.clone(), &events);
+        let health = spawn_recorder(pool.clone(), &events);
+        let directory = for_pool(&pool);
+        let load = spawn_load_sync(directory.clone(), directory_sync_interval());
+        let signing = RequestVerifier::new(config.check_key.clone(), signatures_required());
+
         // Return Configuration
         MeshState {
             keys: config,
+            directory,
             pool: pool,
             client: client,
+            health_client,
 
             instance_stack: Arc::new(Mutex::new(HashMap::new())),
-            task_queue: Arc::new(Mutex::new(VecDeque::new()))
+            task_queue: Arc::new(Mutex::new(VecDeque::new())),
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            events,
+            webhook_deliveries,
+            audit,
+            health,
+            load,
+            retries: retry_policies(&health_checks),
+            registrations: RegistrationLimiter::new(registration_limits()),
+            signing,
+            health_checks
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,296 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, fmt};
+
+use chrono::Utc;
+use futures_timer::Delay;
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::Mutex;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::events::{EventBus, NodeEvent};
+
+/// Number of attempts made to deliver an event to a target before giving up.
+const DELIVERY_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry, doubled after every further failure.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Number of deliveries kept in the in-memory delivery log.
+const DELIVERY_LOG_SIZE: usize = 500;
+
+/// An endpoint notified of node lifecycle events, configured in the file pointed to by `$WEBHOOK_CONFIG`:
+///
+/// ```json
+/// [{ "url": "https://example.com/hook", "secret": "...", "events": ["instantiation_failed"] }]
+/// ```
+///
+/// An empty (or absent) `events` list subscribes the target to every event type.
+#[derive(Deserialize, Clone)]
+pub struct WebhookTarget {
+    pub url: String,
+    pub secret: String,
+    #[serde(default)]
+    pub events: Vec<String>
+}
+
+impl WebhookTarget {
+    fn accepts(&self, event: &NodeEvent) -> bool {
+        self.events.is_empty() || self.events.iter().any(|name| name == event.name())
+    }
+}
+
+impl fmt::Debug for WebhookTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebhookTarget")
+            .field("url", &self.url)
+            .field("secret", &"<redacted>")
+            .field("events", &self.events)
+            .finish()
+    }
+}
+
+/// The outcome of delivering one event to one target.
+#[derive(Serialize, Clone, Debug)]
+pub struct Delivery {
+    pub id: String,
+    pub url: String,
+    pub event: String,
+    pub delivered: bool,
+    pub attempts: u32,
+    pub response_status: Option<u16>,
+    pub error: Option<String>,
+    pub at: i64
+}
+
+/// The most recent deliveries, newest last.
+pub type DeliveryLog = Arc<Mutex<VecDeque<Delivery>>>;
+
+/// Reads the webhook targets from `$WEBHOOK_CONFIG`. Webhooks are optional, so a missing variable means no targets.
+pub fn load_targets() -> Vec<WebhookTarget> {
+    let path = match env::var("WEBHOOK_CONFIG") {
+        Ok(path) => path,
+        Err(_) => return vec![]
+    };
+
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) => panic!("[err]: Unable to read webhook configuration {}: {}", path, err)
+    };
+
+    match serde_json::from_str::<Vec<WebhookTarget>>(&contents) {
+        Ok(targets) => {
+            info!(targets = targets.len(), "Loaded webhook targets");
+            targets
+        },
+        Err(err) => panic!("[err]: Invalid webhook configuration {}: {}", path, err)
+    }
+}
+
+/// Subscribes to the event bus and delivers every event to each target that accepts it.
+/// Each delivery runs on its own task so a slow or failing target does not hold up the others.
+pub fn spawn_dispatcher(
+    bus: &EventBus,
+    targets: Vec<WebhookTarget>,
+    client: Client,
+    log: DeliveryLog
+) {
+    if targets.is_empty() {
+        return;
+    }
+
+    let mut receiver = bus.subscribe();
+
+    tokio::spawn(async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    error!(skipped, "Webhook dispatcher lagged behind, events were not delivered");
+                    continue;
+                },
+                Err(RecvError::Closed) => return
+            };
+
+            for target in targets.iter().filter(|target| target.accepts(&event)) {
+                tokio::spawn(deliver(target.clone(), event.clone(), client.clone(), log.clone(), INITIAL_BACKOFF));
+            }
+        }
+    });
+}
+
+/// POSTs the event to the target, retrying with exponential backoff from `backoff`, and records the outcome.
+async fn deliver(
+    target: WebhookTarget,
+    event: NodeEvent,
+    client: Client,
+    log: DeliveryLog,
+    mut backoff: Duration
+) {
+    let body = match serde_json::to_string(&event) {
+        Ok(body) => body,
+        Err(err) => {
+            error!(error = %err, "Unable to serialize webhook event");
+            return;
+        }
+    };
+
+    let mut attempts = 0;
+
+    let (delivered, response_status, error) = loop {
+        attempts += 1;
+
+        let signature = signature_header(&target.secret, Utc::now().timestamp(), &body);
+
+        let result = client.post(&target.url)
+            .header("Content-Type", "application/json")
+            .header("X-Reseda-Event", event.name())
+            .header("X-Reseda-Signature", signature)
+            .body(body.clone())
+            .send().await;
+
+        let (response_status, error) = match result {
+            Ok(response) if response.status().is_success() => {
+                break (true, Some(response.status().as_u16()), None)
+            },
+            Ok(response) => (Some(response.status().as_u16()), Some(format!("target responded with {}", response.status()))),
+            Err(err) => (None, Some(err.to_string()))
+        };
+
+        if attempts >= DELIVERY_ATTEMPTS {
+            break (false, response_status, error)
+        }
+
+        warn!(url = %target.url, event = event.name(), attempts, error = ?error, "Webhook delivery failed, retrying");
+
+        Delay::new(backoff).await;
+        backoff *= 2;
+    };
+
+    if !delivered {
+        error!(url = %target.url, event = event.name(), attempts, error = ?error, "Webhook delivery abandoned");
+    }
+
+    let mut log_lock = log.lock().await;
+
+    if log_lock.len() >= DELIVERY_LOG_SIZE {
+        log_lock.pop_front();
+    }
+
+    log_lock.push_back(Delivery {
+        id: Uuid::new_v4().to_string(),
+        url: target.url,
+        event: event.name().to_string(),
+        delivered,
+        attempts,
+        response_status,
+        error,
+        at: Utc::now().timestamp_millis()
+    });
+}
+
+/// The `X-Reseda-Signature` of `body`: `t={timestamp},v1={hex digest}`, where the digest is the HMAC-SHA256
+/// of `"{timestamp}.{body}"` under the target's secret, so receivers can also reject replays.
+fn signature_header(secret: &String, timestamp: i64, body: &String) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+
+    format!("t={},v1={}", timestamp, hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Instant;
+
+    use warp::Filter;
+    use warp::http::StatusCode;
+
+    use super::*;
+
+    const BACKOFF: Duration = Duration::from_millis(10);
+
+    fn event() -> NodeEvent {
+        NodeEvent::Purged {
+            node: "netherlands-node".to_string(),
+            ip: "10.0.0.1".to_string(),
+            country: "Netherlands".to_string(),
+            at: 0
+        }
+    }
+
+    /// A target which answers `500` to its first `failures` requests and `204` after, counting every request.
+    fn target(failures: u32) -> (WebhookTarget, Arc<AtomicU32>) {
+        let received = Arc::new(AtomicU32::new(0));
+        let counter = received.clone();
+
+        let route = warp::post().map(move || {
+            match counter.fetch_add(1, Ordering::SeqCst) < failures {
+                true => StatusCode::INTERNAL_SERVER_ERROR,
+                false => StatusCode::NO_CONTENT
+            }
+        });
+
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let target = WebhookTarget {
+            url: format!("http://{}/hook", address),
+            secret: "whsec_test".to_string(),
+            events: vec![]
+        };
+
+        (target, received)
+    }
+
+    #[test]
+    fn signs_the_timestamp_and_body() {
+        let body = r#"{"event":"node_purged"}"#.to_string();
+
+        assert_eq!(
+            signature_header(&"whsec_test".to_string(), 1700000000, &body),
+            "t=1700000000,v1=77534751673d36fc14a7303894d04262d53e073f6a2af8e2157605dd3832df78"
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_until_the_target_accepts() {
+        let (target, received) = target(2);
+        let log = DeliveryLog::default();
+
+        deliver(target, event(), Client::new(), log.clone(), BACKOFF).await;
+
+        let delivery = log.lock().await.pop_back().unwrap();
+        assert!(delivery.delivered);
+        assert_eq!(delivery.attempts, 3);
+        assert_eq!(delivery.response_status, Some(204));
+        assert_eq!(received.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_every_attempt_backing_off_in_between() {
+        let (target, received) = target(u32::MAX);
+        let log = DeliveryLog::default();
+
+        let started = Instant::now();
+        deliver(target, event(), Client::new(), log.clone(), BACKOFF).await;
+
+        // 10, 20, 40 and 80ms between the five attempts.
+        assert!(started.elapsed() >= BACKOFF * 15);
+
+        let delivery = log.lock().await.pop_back().unwrap();
+        assert!(!delivery.delivered);
+        assert_eq!(delivery.attempts, DELIVERY_ATTEMPTS);
+        assert_eq!(delivery.response_status, Some(500));
+        assert_eq!(received.load(Ordering::SeqCst), DELIVERY_ATTEMPTS);
+    }
+}